@@ -0,0 +1,188 @@
+use crate::download::local_path;
+use crate::CrawlForgeError;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// The kind of archive, going by its file name
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ArchiveFormat {
+    Tar,
+    /// A zstd compressed tar
+    TarZstd,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Returns the format of an archive named path, e.g. "out.tar.zst"
+    pub fn from_path(path: &Path) -> Result<Self, CrawlForgeError> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.ends_with(".tar") {
+            Ok(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Ok(ArchiveFormat::TarZstd)
+        } else if name.ends_with(".zip") {
+            Ok(ArchiveFormat::Zip)
+        } else {
+            Err(CrawlForgeError::ParseFormatError(format!(
+                "{}, archives must be .tar, .tar.zst or .zip",
+                name
+            )))
+        }
+    }
+}
+
+/// Writes files straight into a tar or zip archive
+pub enum Archive<W: Write + Seek> {
+    Tar(tar::Builder<W>),
+    TarZstd(tar::Builder<zstd::Encoder<'static, W>>),
+    Zip(ZipWriter<W>),
+}
+
+impl<W: Write + Seek> Archive<W> {
+    pub fn new(out: W, format: ArchiveFormat) -> Result<Self, CrawlForgeError> {
+        Ok(match format {
+            ArchiveFormat::Tar => Archive::Tar(tar::Builder::new(out)),
+            ArchiveFormat::TarZstd => {
+                let encoder = zstd::Encoder::new(out, 0).map_err(CrawlForgeError::Io)?;
+                Archive::TarZstd(tar::Builder::new(encoder))
+            }
+            ArchiveFormat::Zip => Archive::Zip(ZipWriter::new(out)),
+        })
+    }
+
+    /// Adds a file of size bytes at an index path, modified at date if the
+    /// listing showed it
+    pub fn append<R: Read>(
+        &mut self,
+        path: &str,
+        date: Option<DateTime<Utc>>,
+        size: u64,
+        mut contents: R,
+    ) -> Result<(), CrawlForgeError> {
+        // Archives are extracted under a directory too
+        local_path(Path::new(""), path)?;
+        // Undated files get the Unix epoch, so the same files make the same
+        // archive
+        let date = date.unwrap_or_default();
+        match self {
+            Archive::Tar(builder) => append_tar(builder, path, date, size, contents),
+            Archive::TarZstd(builder) => append_tar(builder, path, date, size, contents),
+            Archive::Zip(zip) => {
+                let mut options =
+                    FileOptions::default().compression_method(CompressionMethod::Deflated);
+                // Zip can't record dates before 1980
+                if let Some(time) = zip_date(date) {
+                    options = options.last_modified_time(time);
+                }
+                zip.start_file(path, options)
+                    .map_err(|err| CrawlForgeError::Io(err.into()))?;
+                io::copy(&mut contents, zip)
+                    .map(drop)
+                    .map_err(CrawlForgeError::Io)
+            }
+        }
+    }
+
+    /// Writes the end of the archive, returning the writer
+    pub fn finish(self) -> Result<W, CrawlForgeError> {
+        match self {
+            Archive::Tar(builder) => builder.into_inner().map_err(CrawlForgeError::Io),
+            Archive::TarZstd(builder) => builder
+                .into_inner()
+                .and_then(|encoder| encoder.finish())
+                .map_err(CrawlForgeError::Io),
+            Archive::Zip(mut zip) => zip.finish().map_err(|err| CrawlForgeError::Io(err.into())),
+        }
+    }
+}
+
+fn append_tar<T: Write, R: Read>(
+    builder: &mut tar::Builder<T>,
+    path: &str,
+    date: DateTime<Utc>,
+    size: u64,
+    contents: R,
+) -> Result<(), CrawlForgeError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(date.timestamp().max(0) as u64);
+    builder
+        .append_data(&mut header, path, contents)
+        .map_err(CrawlForgeError::Io)
+}
+
+fn zip_date(date: DateTime<Utc>) -> Option<zip::DateTime> {
+    let year = u16::try_from(date.year()).ok()?;
+    zip::DateTime::from_date_and_time(
+        year,
+        date.month() as u8,
+        date.day() as u8,
+        date.hour() as u8,
+        date.minute() as u8,
+        date.second() as u8,
+    )
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::io::Cursor;
+
+    fn date() -> Option<DateTime<Utc>> {
+        Some(Utc.with_ymd_and_hms(2020, 6, 4, 10, 11, 12).unwrap())
+    }
+
+    #[test]
+    fn test_archive_format() {
+        let format = |name: &str| ArchiveFormat::from_path(Path::new(name)).ok();
+        assert_eq!(format("out.tar"), Some(ArchiveFormat::Tar));
+        assert_eq!(format("dir/out.tar.zst"), Some(ArchiveFormat::TarZstd));
+        assert_eq!(format("out.zip"), Some(ArchiveFormat::Zip));
+        assert_eq!(format("out.gz"), None);
+    }
+
+    #[test]
+    fn test_archive_tar_zstd() {
+        let mut archive = Archive::new(Cursor::new(Vec::new()), ArchiveFormat::TarZstd).unwrap();
+        archive
+            .append("AGL/foofile", date(), 4, &b"foo\n"[..])
+            .unwrap();
+        archive.append("undated", None, 0, io::empty()).unwrap();
+        assert!(archive.append("../escape", None, 0, io::empty()).is_err());
+        let compressed = archive.finish().unwrap().into_inner();
+
+        let tar = zstd::decode_all(compressed.as_slice()).unwrap();
+        let mut tar = tar::Archive::new(tar.as_slice());
+        let mut files = tar.entries().unwrap();
+        let mut file = files.next().unwrap().unwrap();
+        assert_eq!(file.path().unwrap(), Path::new("AGL/foofile"));
+        assert_eq!(file.header().mtime().unwrap(), 1591265472);
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "foo\n");
+        let file = files.next().unwrap().unwrap();
+        assert_eq!(file.header().mtime().unwrap(), 0);
+        assert!(files.next().is_none());
+    }
+
+    #[test]
+    fn test_archive_zip() {
+        let mut archive = Archive::new(Cursor::new(Vec::new()), ArchiveFormat::Zip).unwrap();
+        archive
+            .append("AGL/foofile", date(), 4, &b"foo\n"[..])
+            .unwrap();
+        let mut zip = zip::ZipArchive::new(archive.finish().unwrap()).unwrap();
+        let mut file = zip.by_name("AGL/foofile").unwrap();
+        assert_eq!(file.last_modified().hour(), 10);
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "foo\n");
+    }
+}
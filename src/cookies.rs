@@ -0,0 +1,350 @@
+use crate::CrawlForgeError;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// A single cookie, as stored in a Netscape cookies.txt
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cookie {
+    /// Domain, without a leading "."
+    pub domain: String,
+    /// Whether subdomains of domain also get the cookie
+    pub include_subdomains: bool,
+    pub path: String,
+    pub secure: bool,
+    pub http_only: bool,
+    /// Unix time the cookie expires, or 0 for a session cookie
+    pub expires: i64,
+    pub name: String,
+    pub value: String,
+}
+
+impl Cookie {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+
+    fn matches(&self, u: &url::Url, now: i64) -> bool {
+        let host = match u.host_str() {
+            Some(host) => host,
+            None => return false,
+        };
+        let (host, domain) = (host.to_ascii_lowercase(), self.domain.to_ascii_lowercase());
+        let domain_ok =
+            host == domain || (self.include_subdomains && host.ends_with(&format!(".{}", domain)));
+        let path_ok = path_matches(u.path(), &self.path);
+        let secure_ok = !self.secure || u.scheme() == "https";
+        domain_ok && path_ok && secure_ok && !self.is_expired(now)
+    }
+}
+
+/// Cookies sent with each request, updated from Set-Cookie responses
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    /// Loads a Netscape cookies.txt, as exported by browser extensions, curl
+    /// --cookie-jar and wget --save-cookies
+    pub fn load(path: &Path) -> Result<Self, CrawlForgeError> {
+        let contents = fs::read_to_string(path).map_err(CrawlForgeError::Io)?;
+        Ok(Self::parse(&contents))
+    }
+
+    /// Saves the jar as a Netscape cookies.txt, which only the user can read
+    /// if it's new, as curl and wget do
+    pub fn save(&self, path: &Path) -> Result<(), CrawlForgeError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(self.to_string().as_bytes()))
+            .map_err(CrawlForgeError::Io)
+    }
+
+    /// Parses the contents of a Netscape cookies.txt, skipping malformed lines
+    pub fn parse(contents: &str) -> Self {
+        let cookies = contents
+            .lines()
+            .filter_map(|line| {
+                let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                    Some(line) => (line, true),
+                    None => (line, false),
+                };
+                if line.starts_with('#') {
+                    return None;
+                }
+                let fields: Vec<_> = line.split('\t').collect();
+                match fields.as_slice() {
+                    [domain, subdomains, path, secure, expires, name, value] => Some(Cookie {
+                        domain: domain.trim_start_matches('.').to_string(),
+                        include_subdomains: subdomains.eq_ignore_ascii_case("TRUE"),
+                        path: path.to_string(),
+                        secure: secure.eq_ignore_ascii_case("TRUE"),
+                        http_only,
+                        expires: expires.parse().ok()?,
+                        name: name.to_string(),
+                        value: value.to_string(),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect();
+        CookieJar { cookies }
+    }
+
+    /// Returns the Cookie header value for a request to u, if any cookies match
+    pub fn header(&self, u: &url::Url) -> Option<String> {
+        let now = Utc::now().timestamp();
+        let pairs: Vec<_> = self
+            .cookies
+            .iter()
+            .filter(|c| c.matches(u, now))
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect();
+        if pairs.is_empty() {
+            None
+        } else {
+            Some(pairs.join("; "))
+        }
+    }
+
+    /// Updates the jar from a Set-Cookie header in the response to u. Returns
+    /// whether anything changed.
+    pub fn set_cookie(&mut self, u: &url::Url, header: &str) -> bool {
+        let cookie = match parse_set_cookie(u, header, Utc::now().timestamp()) {
+            Some(cookie) => cookie,
+            None => return false,
+        };
+
+        let existing = self.cookies.iter().position(|c| {
+            c.domain == cookie.domain && c.path == cookie.path && c.name == cookie.name
+        });
+        let now = Utc::now().timestamp();
+        match existing {
+            Some(i) if self.cookies[i] == cookie => false,
+            Some(i) if cookie.is_expired(now) => {
+                self.cookies.remove(i);
+                true
+            }
+            Some(i) => {
+                self.cookies[i] = cookie;
+                true
+            }
+            None if cookie.is_expired(now) => false,
+            None => {
+                self.cookies.push(cookie);
+                true
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for CookieJar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = String::from("# Netscape HTTP Cookie File\n");
+        for c in &self.cookies {
+            let bool_str = |b: bool| if b { "TRUE" } else { "FALSE" };
+            let _ = writeln!(
+                s,
+                "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                if c.http_only { "#HttpOnly_" } else { "" },
+                if c.include_subdomains { "." } else { "" },
+                c.domain,
+                bool_str(c.include_subdomains),
+                c.path,
+                bool_str(c.secure),
+                c.expires,
+                c.name,
+                c.value
+            );
+        }
+        f.write_str(&s)
+    }
+}
+
+/// Parses a Set-Cookie header from the response to u
+fn parse_set_cookie(u: &url::Url, header: &str, now: i64) -> Option<Cookie> {
+    let mut parts = header.split(';');
+    let mut name_value = parts.next()?.splitn(2, '=');
+    let name = name_value.next()?.trim();
+    let value = name_value.next()?.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    // Default path is the directory of the request path
+    let default_path = match u.path().rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => u.path()[..i].to_string(),
+    };
+    let mut cookie = Cookie {
+        domain: u.host_str()?.to_string(),
+        include_subdomains: false,
+        path: default_path,
+        secure: false,
+        http_only: false,
+        expires: 0,
+        name: name.to_string(),
+        value: value.to_string(),
+    };
+
+    let mut max_age = None;
+    for attr in parts {
+        let mut kv = attr.splitn(2, '=');
+        let key = kv.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let val = kv.next().unwrap_or_default().trim();
+        match key.as_str() {
+            "domain" if !val.is_empty() => {
+                let domain = val.trim_start_matches('.').to_ascii_lowercase();
+                // A server can only set cookies for itself and its parents
+                if !domain_matches(u, &domain) {
+                    return None;
+                }
+                cookie.domain = domain;
+                cookie.include_subdomains = true;
+            }
+            "path" if val.starts_with('/') => cookie.path = val.to_string(),
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            "max-age" => max_age = val.parse::<i64>().ok(),
+            "expires" => {
+                if let Some(expires) = parse_cookie_date(val) {
+                    cookie.expires = expires;
+                }
+            }
+            _ => (),
+        }
+    }
+    if let Some(max_age) = max_age {
+        // Expire immediately, but 0 would mean a session cookie
+        cookie.expires = if max_age <= 0 { 1 } else { now + max_age };
+    }
+    Some(cookie)
+}
+
+/// Whether a cookie for cookie_path is sent with a request for path, as RFC
+/// 6265 5.1.4 says, so a cookie for /foo isn't sent to /foobar
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path.strip_prefix(cookie_path)
+        .is_some_and(|rest| rest.is_empty() || cookie_path.ends_with('/') || rest.starts_with('/'))
+}
+
+/// Whether the host of u is domain or a subdomain of it, as RFC 6265 5.1.3
+/// requires of a Set-Cookie Domain
+fn domain_matches(u: &url::Url, domain: &str) -> bool {
+    match u.host() {
+        Some(url::Host::Domain(host)) => {
+            let host = host.to_ascii_lowercase();
+            host == domain || host.ends_with(&format!(".{}", domain))
+        }
+        // IP addresses have no subdomains
+        Some(host) => host.to_string() == domain,
+        None => false,
+    }
+}
+
+/// Parses the date formats seen in Set-Cookie Expires attributes
+fn parse_cookie_date(s: &str) -> Option<i64> {
+    if let Ok(date) = DateTime::parse_from_rfc2822(s) {
+        return Some(date.timestamp());
+    }
+    NaiveDateTime::parse_from_str(s, "%a, %d-%b-%Y %H:%M:%S GMT")
+        .ok()
+        .map(|d| d.and_utc().timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOKIES_TXT: &str = "# Netscape HTTP Cookie File
+.example.com\tTRUE\t/\tFALSE\t0\tsso\tabc
+#HttpOnly_opengrok.example.com\tFALSE\t/xref\tTRUE\t4102444800\tJSESSIONID\tdef
+bad line
+";
+
+    #[test]
+    fn test_cookie_jar_parse_roundtrip() {
+        let jar = CookieJar::parse(COOKIES_TXT);
+        assert_eq!(jar.cookies.len(), 2);
+        assert!(jar.cookies[1].http_only);
+        assert_eq!(CookieJar::parse(&jar.to_string()), jar);
+    }
+
+    #[test]
+    fn test_cookie_jar_header() {
+        let jar = CookieJar::parse(COOKIES_TXT);
+        let u = url::Url::parse("https://opengrok.example.com/xref/foo/").unwrap();
+        assert_eq!(jar.header(&u).as_deref(), Some("sso=abc; JSESSIONID=def"));
+
+        // JSESSIONID is secure, and only for /xref
+        let u = url::Url::parse("http://opengrok.example.com/xref/foo/").unwrap();
+        assert_eq!(jar.header(&u).as_deref(), Some("sso=abc"));
+        let u = url::Url::parse("https://opengrok.example.com/raw/foo").unwrap();
+        assert_eq!(jar.header(&u).as_deref(), Some("sso=abc"));
+
+        let u = url::Url::parse("https://github.com/").unwrap();
+        assert_eq!(jar.header(&u), None);
+    }
+
+    #[test]
+    fn test_cookie_jar_set_cookie() {
+        let mut jar = CookieJar::parse(COOKIES_TXT);
+        let u = url::Url::parse("https://opengrok.example.com/xref/foo/").unwrap();
+        assert!(jar.set_cookie(&u, "JSESSIONID=ghi; Path=/xref; Secure; HttpOnly"));
+        assert!(!jar.set_cookie(&u, "JSESSIONID=ghi; Path=/xref; Secure; HttpOnly"));
+        assert_eq!(jar.header(&u).as_deref(), Some("sso=abc; JSESSIONID=ghi"));
+
+        assert!(jar.set_cookie(&u, "sso=; Domain=.example.com; Path=/; Max-Age=0"));
+        assert_eq!(jar.header(&u).as_deref(), Some("JSESSIONID=ghi"));
+
+        // Servers can't set cookies for other domains
+        assert!(!jar.set_cookie(&u, "evil=1; Domain=github.com"));
+        assert!(!jar.set_cookie(&u, "evil=1; Domain=ample.com"));
+        assert!(!jar.set_cookie(&u, "evil=1; Domain=grok.example.com"));
+        assert_eq!(jar.header(&u).as_deref(), Some("JSESSIONID=ghi"));
+    }
+
+    #[test]
+    fn test_cookie_matches() {
+        let cookie =
+            CookieJar::parse("Example.COM\tFALSE\t/xref\tFALSE\t0\ts\t1\n").cookies[0].clone();
+        let matches = |u: &str| cookie.matches(&url::Url::parse(u).unwrap(), 0);
+        assert!(matches("http://example.com/xref"));
+        assert!(matches("http://EXAMPLE.com/xref/foo"));
+        assert!(!matches("http://example.com/xrefs"));
+        assert!(!matches("http://example.com/"));
+        assert!(path_matches("/xref/foo", "/xref/"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cookie_jar_save_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookies.txt");
+        CookieJar::parse(COOKIES_TXT).save(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_parse_cookie_date() {
+        assert_eq!(
+            parse_cookie_date("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(1445412480)
+        );
+        assert_eq!(
+            parse_cookie_date("Wed, 21-Oct-2015 07:28:00 GMT"),
+            Some(1445412480)
+        );
+    }
+}
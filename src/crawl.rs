@@ -0,0 +1,425 @@
+use crate::client::Client;
+use crate::filter::{MetadataFilter, PathFilter};
+use crate::output::IndexWriter;
+use crate::robots::Robots;
+use crate::since::{Change, PreviousIndex};
+use crate::state::CrawlState;
+use crate::{
+    canonical_url, check_login_page, forge_path, parse_listing, CrawlForgeError, Entry, ForgeKind,
+    Listing,
+};
+use log::warn;
+use std::collections::HashSet;
+use std::io::Write;
+
+/// Recursively crawls the directories of a forge
+pub struct Crawler {
+    forge: ForgeKind,
+    client: Client,
+
+    /// Where the crawl's progress is recorded, if it's resumable
+    pub state: Option<CrawlState>,
+
+    /// The forge's robots.txt rules, if we're respecting them
+    pub robots: Option<Robots>,
+
+    /// Which directories to crawl and files to index
+    pub filter: PathFilter,
+
+    /// Which files to index, by size and date
+    pub metadata_filter: MetadataFilter,
+
+    /// The previous index, if only changes since it should be indexed
+    pub since: Option<PreviousIndex>,
+
+    /// Don't crawl directories more than this many levels below the root
+    pub max_depth: Option<usize>,
+
+    /// Stop after indexing this many files
+    pub max_files: Option<usize>,
+
+    /// Stop after listing this many directories
+    pub max_dirs: Option<usize>,
+
+    /// Keep the indexed entries, for entries()
+    pub keep_entries: bool,
+
+    /// Record directories which fail and carry on, instead of stopping.
+    /// Authentication failures always stop the crawl.
+    pub keep_going: bool,
+
+    failures: Vec<(url::Url, CrawlForgeError)>,
+    entries: Vec<Entry>,
+    files: usize,
+    dirs: usize,
+    stopped_by: Option<&'static str>,
+
+    /// Canonical URL of the root, which the crawl doesn't leave
+    root: Option<url::Url>,
+
+    /// Canonical URLs of the directories crawled and files indexed, so that
+    /// cyclic or duplicate links are only followed once
+    visited: HashSet<url::Url>,
+    indexed: HashSet<url::Url>,
+}
+
+impl Crawler {
+    pub fn new(forge: ForgeKind, client: Client) -> Self {
+        Crawler {
+            forge,
+            client,
+            state: None,
+            robots: None,
+            filter: PathFilter::default(),
+            metadata_filter: MetadataFilter::default(),
+            since: None,
+            max_depth: None,
+            max_files: None,
+            max_dirs: None,
+            keep_entries: false,
+            keep_going: false,
+            failures: Vec::new(),
+            entries: Vec::new(),
+            files: 0,
+            dirs: 0,
+            stopped_by: None,
+            root: None,
+            visited: HashSet::new(),
+            indexed: HashSet::new(),
+        }
+    }
+
+    /// Returns the directories skipped by keep_going, and why
+    pub fn failures(&self) -> &[(url::Url, CrawlForgeError)] {
+        &self.failures
+    }
+
+    /// Returns the client used to crawl
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Returns the client, to crawl something else with it
+    pub fn into_client(self) -> Client {
+        self.client
+    }
+
+    /// Returns the entries indexed, if keep_entries is set
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Returns the option which stopped the crawl early, e.g. "--max-files"
+    pub fn stopped_by(&self) -> Option<&'static str> {
+        self.stopped_by
+    }
+
+    /// Crawls root and all of its subdirectories, writing each raw file to
+    /// index. Links to directories outside root, such as "../", aren't
+    /// followed.
+    pub fn crawl<W: Write>(
+        &mut self,
+        root: url::Url,
+        index: &mut IndexWriter<W>,
+    ) -> Result<(), CrawlForgeError> {
+        self.root = Some(canonical_url(&root));
+        self.crawl_dir(root, 0, index)
+    }
+
+    fn crawl_dir<W: Write>(
+        &mut self,
+        root: url::Url,
+        depth: usize,
+        index: &mut IndexWriter<W>,
+    ) -> Result<(), CrawlForgeError> {
+        if self.stopped_by.is_some() || !self.visited.insert(canonical_url(&root)) {
+            return Ok(());
+        }
+        if self.max_dirs.is_some_and(|max| self.dirs >= max) {
+            self.stopped_by = Some("--max-dirs");
+            return Ok(());
+        }
+
+        if let Some(robots) = &self.robots {
+            if !robots.is_allowed(root.path()) {
+                warn!("{}: disallowed by robots.txt", root);
+                return Ok(());
+            }
+        }
+
+        let listing = match self.listing(root.clone()) {
+            Ok(listing) => listing,
+            // Everything after an expired session would fail too, so stop
+            Err(err @ CrawlForgeError::AuthenticationFailed(_, _)) => return Err(err),
+            // A 403 on the root means the credentials are wrong, below it
+            // it may just be one directory we aren't allowed into
+            Err(err @ CrawlForgeError::Forbidden(_)) if depth == 0 => return Err(err),
+            Err(err) if self.keep_going => {
+                warn!("{}: {}", root, err);
+                self.failures.push((root, err));
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        self.dirs += 1;
+
+        if let Some(since) = &mut self.since {
+            for entry in &listing.entries {
+                since.present(entry);
+            }
+        }
+        for entry in &listing.entries {
+            let canonical = canonical_url(&entry.url);
+            if !self.filter.index_file(&entry.path)
+                || !self.metadata_filter.index_file(entry)
+                || self.indexed.contains(&canonical)
+            {
+                continue;
+            }
+            if self.max_files.is_some_and(|max| self.files >= max) {
+                self.stopped_by = Some("--max-files");
+                return Ok(());
+            }
+            let change = self.since.as_mut().map(|since| since.check(entry));
+            match change {
+                Some(change) => index.write_change(entry, change)?,
+                None => index.write_entry(entry)?,
+            }
+            self.indexed.insert(canonical);
+            // Unchanged files are only carried forward in the jsonl index
+            if change == Some(Change::Unchanged) {
+                continue;
+            }
+            if self.keep_entries {
+                self.entries.push(entry.clone());
+            }
+            self.files += 1;
+        }
+        let forge = self.forge;
+        if let Some(since) = &mut self.since {
+            let subdirs = listing
+                .dirs
+                .iter()
+                .map(|d| forge_path(forge, d.path()))
+                .collect();
+            since.listed(&forge_path(forge, root.path()), subdirs);
+        }
+
+        if self.max_depth.is_some_and(|max| depth >= max) {
+            return Ok(());
+        }
+        for d in listing.dirs {
+            let below_root = self
+                .root
+                .as_ref()
+                .is_none_or(|root| is_below(&canonical_url(&d), root));
+            let path = forge_path(self.forge, d.path());
+            if !below_root || !self.filter.crawl_dir(&path) {
+                continue;
+            }
+            if let (Some(since), Some(date)) = (&mut self.since, listing.dir_dates.get(&path)) {
+                if let Some(unchanged) = since.dir_unchanged(&path, *date) {
+                    for entry in &unchanged {
+                        index.write_change(entry, Change::Unchanged)?;
+                        self.indexed.insert(canonical_url(&entry.url));
+                    }
+                    continue;
+                }
+            }
+            self.crawl_dir(d, depth + 1, index)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the listing of a directory, from the state file if a previous
+    /// run already fetched it
+    fn listing(&mut self, dir: url::Url) -> Result<Listing, CrawlForgeError> {
+        if let Some(listing) = self.state.as_ref().and_then(|s| s.get(&dir)) {
+            return Ok(listing.clone());
+        }
+
+        let (final_url, body) = self.client.get_page(&dir)?;
+        check_login_page(self.forge, &dir, &final_url, &body)?;
+        let listing = parse_listing(self.forge, &dir, body.as_str())?;
+
+        if let Some(state) = self.state.as_mut() {
+            state.record(&listing)?;
+        }
+        Ok(listing)
+    }
+}
+
+/// Returns whether the canonical URL u is root or one of its subdirectories
+fn is_below(u: &url::Url, root: &url::Url) -> bool {
+    let root_path = root.path().trim_end_matches('/');
+    u.origin() == root.origin()
+        && u.path()
+            .strip_prefix(root_path)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientOpt;
+    use crate::output::OutputFormat;
+    use crate::tests::data::BODY_OPENGROK_SIZES;
+    use crate::tests::server::{failing_server, scripted_server};
+    use structopt::StructOpt;
+
+    fn crawler() -> Crawler {
+        let opt = ClientOpt::from_iter(&["crawlforge", "--retries", "0"]);
+        let client = Client::new(&opt, &url("")).unwrap();
+        Crawler::new(ForgeKind::OpenGrok, client)
+    }
+
+    /// Returns the URL of path on a forge where fetching anything fails
+    fn url(path: &str) -> url::Url {
+        failing_server().join("xref/").unwrap().join(path).unwrap()
+    }
+
+    /// Returns a crawler which reads this tree from its state file, rather
+    /// than fetching it:
+    ///
+    ///     f1  a/f2  a/x/f3  b/f4
+    ///
+    /// b/ also links back to the root and a/, and to f1 under another name.
+    /// The state file is in the returned directory, removed when it's dropped.
+    fn crawler_with_tree() -> (tempfile::TempDir, Crawler) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = CrawlState::open(&dir.path().join("state.jsonl")).unwrap();
+        let tree: &[(&str, &[&str], &[&str])] = &[
+            ("", &["a/", "b/"], &["f1"]),
+            ("a/", &["a/x/"], &["a/f2"]),
+            ("a/x/", &[], &["a/x/f3"]),
+            ("b/", &["", "a"], &["b/f4", "%66%31"]),
+        ];
+        for (dir, dirs, files) in tree {
+            state
+                .record(&Listing {
+                    url: url(dir),
+                    dirs: dirs.iter().map(|d| url(d)).collect(),
+                    entries: files
+                        .iter()
+                        .map(|f| Entry {
+                            path: f.to_string(),
+                            url: url(f),
+                            forge: ForgeKind::OpenGrok,
+                            git_ref: None,
+                            sha: None,
+                            size: None,
+                            size_rounded: false,
+                            date: None,
+                        })
+                        .collect(),
+                    dir_dates: Default::default(),
+                })
+                .unwrap();
+        }
+        let mut crawler = crawler();
+        crawler.state = Some(state);
+        (dir, crawler)
+    }
+
+    /// Crawls the tree, returning the indexed paths
+    fn crawl_tree(crawler: &mut Crawler) -> Vec<String> {
+        let mut index = IndexWriter::new(Vec::new(), OutputFormat::Plain);
+        crawler.crawl(url(""), &mut index).unwrap();
+        String::from_utf8(index.into_inner())
+            .unwrap()
+            .lines()
+            .map(|l| l.trim_start_matches(url("").as_str()).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_crawl_tree() {
+        let (_dir, mut crawler) = crawler_with_tree();
+        assert_eq!(crawl_tree(&mut crawler), ["f1", "a/f2", "a/x/f3", "b/f4"]);
+        assert_eq!(crawler.stopped_by(), None);
+    }
+
+    #[test]
+    fn test_crawl_max_depth() {
+        let (_dir, mut crawler) = crawler_with_tree();
+        crawler.max_depth = Some(1);
+        assert_eq!(crawl_tree(&mut crawler), ["f1", "a/f2", "b/f4"]);
+    }
+
+    #[test]
+    fn test_crawl_max_files() {
+        let (_dir, mut crawler) = crawler_with_tree();
+        crawler.max_files = Some(2);
+        assert_eq!(crawl_tree(&mut crawler), ["f1", "a/f2"]);
+        assert_eq!(crawler.stopped_by(), Some("--max-files"));
+    }
+
+    #[test]
+    fn test_crawl_max_dirs() {
+        let (_dir, mut crawler) = crawler_with_tree();
+        crawler.max_dirs = Some(3);
+        assert_eq!(crawl_tree(&mut crawler), ["f1", "a/f2", "a/x/f3"]);
+        assert_eq!(crawler.stopped_by(), Some("--max-dirs"));
+    }
+
+    #[test]
+    fn test_is_below() {
+        let root = canonical_url(&url("AGL/metalayers/"));
+        let below = |path: &str| is_below(&canonical_url(&url(path)), &root);
+        assert!(below("AGL/metalayers/"));
+        assert!(below("AGL/metalayers/./"));
+        assert!(below("AGL/metalayers/sub/"));
+        assert!(!below("AGL/metalayers/../"));
+        assert!(!below("AGL/metalayers-extra/"));
+        assert!(!below("AGL/"));
+    }
+
+    #[test]
+    fn test_crawl_error_stops() {
+        let mut crawler = crawler();
+        let mut index = IndexWriter::new(Vec::new(), OutputFormat::Plain);
+        let root = url("");
+        assert!(crawler.crawl(root, &mut index).is_err());
+    }
+
+    #[test]
+    fn test_crawl_keep_going() {
+        let mut crawler = crawler();
+        crawler.keep_going = true;
+        let mut index = IndexWriter::new(Vec::new(), OutputFormat::Plain);
+        let root = url("");
+        crawler.crawl(root.clone(), &mut index).unwrap();
+        assert_eq!(crawler.failures().len(), 1);
+        assert_eq!(crawler.failures()[0].0, root);
+    }
+
+    #[test]
+    fn test_crawl_keep_going_forbidden() {
+        let listing = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            BODY_OPENGROK_SIZES.len(),
+            BODY_OPENGROK_SIZES
+        );
+        let forbidden = "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        let (base, server) = scripted_server(&[&listing, forbidden]);
+        let root = base.join("xref/AGL/metalayers/").unwrap();
+        let opt = ClientOpt::from_iter(&["crawlforge", "--retries", "0"]);
+        let mut crawler = Crawler::new(ForgeKind::OpenGrok, Client::new(&opt, &root).unwrap());
+        crawler.keep_going = true;
+        let mut index = IndexWriter::new(Vec::new(), OutputFormat::Plain);
+        crawler.crawl(root.clone(), &mut index).unwrap();
+        // The listing's "../" and "./" links aren't followed
+        let requests = server.join().unwrap();
+        assert!(requests[1].starts_with("GET /xref/AGL/metalayers/sub/ "));
+
+        assert_eq!(
+            String::from_utf8(index.into_inner())
+                .unwrap()
+                .lines()
+                .count(),
+            2
+        );
+        assert_eq!(crawler.failures().len(), 1);
+        assert_eq!(crawler.failures()[0].0, root.join("sub/").unwrap());
+    }
+}
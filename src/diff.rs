@@ -0,0 +1,268 @@
+use crate::output::parse_jsonl_index;
+use crate::{CrawlForgeError, Entry};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct DiffOpt {
+    /// The older index
+    #[structopt(parse(from_os_str))]
+    pub old: PathBuf,
+
+    /// The newer index
+    #[structopt(parse(from_os_str))]
+    pub new: PathBuf,
+
+    /// The diff format: text or json
+    #[structopt(long, default_value = "text")]
+    pub format: DiffFormat,
+}
+
+/// The format of the diff between two indexes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DiffFormat {
+    /// One "A", "D" or "M" and path per line, like git diff --name-status
+    Text,
+    /// A single JSON object
+    Json,
+}
+
+impl FromStr for DiffFormat {
+    type Err = CrawlForgeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(DiffFormat::Text),
+            "json" => Ok(DiffFormat::Json),
+            _ => Err(CrawlForgeError::ParseFormatError(s.to_string())),
+        }
+    }
+}
+
+/// A file whose size, date, blob SHA or ref differs between the indexes
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Changed {
+    pub old: Entry,
+    pub new: Entry,
+}
+
+/// The differences between two indexes, matching entries by path so that a
+/// new ref in the URLs doesn't make every file look new
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct IndexDiff {
+    pub added: Vec<Entry>,
+    pub removed: Vec<Entry>,
+    pub changed: Vec<Changed>,
+}
+
+impl IndexDiff {
+    /// Compares the entries of two indexes
+    pub fn new(old: Vec<Entry>, new: Vec<Entry>) -> Self {
+        let mut old: BTreeMap<_, _> = old.into_iter().map(|e| (e.path.clone(), e)).collect();
+        let mut diff = IndexDiff::default();
+        for entry in new {
+            match old.remove(&entry.path) {
+                None => diff.added.push(entry),
+                Some(prev) if differs(&prev, &entry) => diff.changed.push(Changed {
+                    old: prev,
+                    new: entry,
+                }),
+                Some(_) => (),
+            }
+        }
+        diff.removed = old.into_values().collect();
+        diff.added.sort_by(|a, b| a.path.cmp(&b.path));
+        diff.changed.sort_by(|a, b| a.new.path.cmp(&b.new.path));
+        diff
+    }
+
+    /// Reads and compares two index files
+    pub fn from_files(opt: &DiffOpt) -> Result<Self, CrawlForgeError> {
+        let read = |path| {
+            fs::read_to_string(path)
+                .map_err(CrawlForgeError::Io)
+                .and_then(|contents| parse_jsonl_index(&contents))
+        };
+        Ok(IndexDiff::new(read(&opt.old)?, read(&opt.new)?))
+    }
+
+    /// Writes the diff in format
+    pub fn write<W: Write>(&self, mut out: W, format: DiffFormat) -> Result<(), CrawlForgeError> {
+        match format {
+            DiffFormat::Text => self.write_text(&mut out).map_err(CrawlForgeError::Io),
+            DiffFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, self).map_err(CrawlForgeError::Json)?;
+                writeln!(out).map_err(CrawlForgeError::Io)
+            }
+        }
+    }
+
+    fn write_text<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let mut lines: Vec<(&str, String)> = Vec::new();
+        for e in &self.added {
+            lines.push((&e.path, format!("A\t{}", e.path)));
+        }
+        for e in &self.removed {
+            lines.push((&e.path, format!("D\t{}", e.path)));
+        }
+        for c in &self.changed {
+            let mut details = Vec::new();
+            if c.old.size != c.new.size {
+                details.push(format!(
+                    "size {} -> {}",
+                    optional(c.old.size),
+                    optional(c.new.size)
+                ));
+            }
+            if c.old.date != c.new.date {
+                details.push(format!(
+                    "date {} -> {}",
+                    optional(c.old.date.map(|d| d.date_naive())),
+                    optional(c.new.date.map(|d| d.date_naive()))
+                ));
+            }
+            if sha_differs(&c.old, &c.new) {
+                details.push(format!(
+                    "sha {} -> {}",
+                    optional(short_sha(&c.old)),
+                    optional(short_sha(&c.new))
+                ));
+            }
+            if c.old.git_ref != c.new.git_ref {
+                details.push(format!(
+                    "ref {} -> {}",
+                    optional(c.old.git_ref.as_deref()),
+                    optional(c.new.git_ref.as_deref())
+                ));
+            }
+            lines.push((
+                &c.new.path,
+                format!("M\t{}\t({})", c.new.path, details.join(", ")),
+            ));
+        }
+        lines.sort();
+        for (_, line) in lines {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether an entry's file differs between the indexes. Blob SHAs
+/// are only compared if both indexes have them.
+fn differs(old: &Entry, new: &Entry) -> bool {
+    old.size != new.size
+        || old.date != new.date
+        || old.git_ref != new.git_ref
+        || sha_differs(old, new)
+}
+
+fn sha_differs(old: &Entry, new: &Entry) -> bool {
+    match (&old.sha, &new.sha) {
+        (Some(old), Some(new)) => !old.eq_ignore_ascii_case(new),
+        _ => false,
+    }
+}
+
+/// Returns the blob SHA of an entry abbreviated as git does
+fn short_sha(entry: &Entry) -> Option<&str> {
+    let sha = entry.sha.as_deref()?;
+    Some(sha.get(..7).unwrap_or(sha))
+}
+
+/// Formats an optional value, with "-" for None
+fn optional<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |v| v.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ForgeKind;
+
+    fn entry(path: &str, size: u64) -> Entry {
+        Entry {
+            path: path.to_string(),
+            url: url::Url::parse("http://10.0.0.1:8080/raw/")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+            forge: ForgeKind::OpenGrok,
+            git_ref: None,
+            sha: None,
+            size: Some(size),
+            size_rounded: false,
+            date: None,
+        }
+    }
+
+    #[test]
+    fn test_index_diff() {
+        let diff = IndexDiff::new(
+            vec![entry("a", 1), entry("b", 2), entry("c", 3)],
+            vec![entry("d", 4), entry("c", 30), entry("a", 1)],
+        );
+        assert_eq!(diff.added, vec![entry("d", 4)]);
+        assert_eq!(diff.removed, vec![entry("b", 2)]);
+        assert_eq!(
+            diff.changed,
+            vec![Changed {
+                old: entry("c", 3),
+                new: entry("c", 30)
+            }]
+        );
+
+        let mut out = Vec::new();
+        diff.write(&mut out, DiffFormat::Text).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "D\tb\nM\tc\t(size 3 -> 30)\nA\td\n"
+        );
+    }
+
+    /// Diffs a single file, changed by f, returning the text output
+    fn diff_one(f: impl Fn(&mut Entry)) -> (IndexDiff, String) {
+        let mut new = entry("a", 1);
+        f(&mut new);
+        let diff = IndexDiff::new(vec![entry("a", 1)], vec![new]);
+        let mut out = Vec::new();
+        diff.write(&mut out, DiffFormat::Text).unwrap();
+        (diff, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_index_diff_sha() {
+        let (diff, text) = diff_one(|e| e.sha = Some("0123456789abcdef".to_string()));
+        // Only one index has a SHA, so there's nothing to compare
+        assert!(diff.changed.is_empty());
+        assert_eq!(text, "");
+
+        let old = Entry {
+            sha: Some("fedcba9876543210".to_string()),
+            ..entry("a", 1)
+        };
+        let new = Entry {
+            sha: Some("0123456789abcdef".to_string()),
+            ..entry("a", 1)
+        };
+        let diff = IndexDiff::new(vec![old], vec![new]);
+        let mut out = Vec::new();
+        diff.write(&mut out, DiffFormat::Text).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "M\ta\t(sha fedcba9 -> 0123456)\n"
+        );
+    }
+
+    #[test]
+    fn test_index_diff_ref() {
+        let (diff, text) = diff_one(|e| e.git_ref = Some("v2".to_string()));
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(text, "M\ta\t(ref - -> v2)\n");
+    }
+}
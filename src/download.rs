@@ -0,0 +1,402 @@
+use crate::archive::{Archive, ArchiveFormat};
+use crate::client::{Client, ClientOpt};
+use crate::lfs::{media_url, parse_pointer, LfsMode, ObjectReader};
+use crate::{check_login_page, is_html_path, looks_like_html, CrawlForgeError, Entry};
+use log::warn;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use structopt::StructOpt;
+
+/// How much of a download to look at to tell whether it's an HTML page
+pub(crate) const SNIFF_LEN: u64 = 64 * 1024;
+
+/// How much of a file to hold in memory while archiving it, before spooling
+/// it to a temporary file
+const SPOOL_LEN: usize = 8 * 1024 * 1024;
+
+#[derive(StructOpt, Debug)]
+pub struct DownloadOpt {
+    /// Index written by crawl --format jsonl
+    #[structopt(parse(from_os_str))]
+    pub index: PathBuf,
+
+    /// Directory to save files under, at their paths in the index
+    #[structopt(short, long, parse(from_os_str), default_value = ".")]
+    pub output_dir: PathBuf,
+
+    /// Write the files into this .tar, .tar.zst or .zip archive instead, dated
+    /// as in the index
+    #[structopt(long, parse(from_os_str))]
+    pub archive: Option<PathBuf>,
+
+    /// Carry on downloading after a file fails, and report failures at the end
+    #[structopt(short, long)]
+    pub keep_going: bool,
+
+    /// Fetch the objects of Git LFS tracked files, rather than failing on the
+    /// pointer files GitHub's raw URLs serve for them. The objects are checked
+    /// against their pointers. Only GitHub is supported, as other forges'
+    /// objects would need the Git LFS batch API.
+    #[structopt(long)]
+    pub lfs: bool,
+
+    #[structopt(flatten)]
+    pub client: ClientOpt,
+}
+
+/// Downloads the files in an index
+pub struct Downloader {
+    client: Client,
+    dir: PathBuf,
+
+    /// Record files which fail and carry on, instead of stopping.
+    /// Authentication failures always stop the download.
+    pub keep_going: bool,
+
+    /// What to do with Git LFS pointers
+    pub lfs: LfsMode,
+
+    failures: Vec<(url::Url, CrawlForgeError)>,
+}
+
+impl Downloader {
+    /// Creates a downloader saving files under dir
+    pub fn new(client: Client, dir: &Path) -> Self {
+        Downloader {
+            client,
+            dir: dir.to_path_buf(),
+            keep_going: false,
+            lfs: LfsMode::Refuse,
+            failures: Vec::new(),
+        }
+    }
+
+    /// Returns the files skipped by keep_going, and why
+    pub fn failures(&self) -> &[(url::Url, CrawlForgeError)] {
+        &self.failures
+    }
+
+    /// Downloads each entry, returning how many were downloaded rather than
+    /// already there
+    pub fn download_all(&mut self, entries: &[Entry]) -> Result<usize, CrawlForgeError> {
+        self.each(entries, |d, entry| d.download(entry))
+    }
+
+    /// Downloads each entry into archive, returning how many were added
+    pub fn archive_all<W: Write + Seek>(
+        &mut self,
+        entries: &[Entry],
+        archive: &mut Archive<W>,
+    ) -> Result<usize, CrawlForgeError> {
+        self.each(entries, |d, entry| {
+            // Archives need the size before the contents, and listings may
+            // round sizes or not show them, so spool the file first. That
+            // also keeps a failed download out of the archive.
+            let mut file = tempfile::spooled_tempfile(SPOOL_LEN);
+            let size = copy_raw(&d.client, entry, d.lfs, &mut file)?;
+            file.seek(SeekFrom::Start(0)).map_err(CrawlForgeError::Io)?;
+            archive.append(&entry.path, entry.date, size, file)?;
+            Ok(true)
+        })
+    }
+
+    /// Downloads each entry into an archive at path, in the format its name
+    /// gives, returning how many were added. The archive is only moved to
+    /// path once it's complete, so a failure never leaves a truncated one.
+    pub fn archive_to(&mut self, entries: &[Entry], path: &Path) -> Result<usize, CrawlForgeError> {
+        let format = ArchiveFormat::from_path(path)?;
+        let part = part_path(path);
+        let archived = File::create(&part)
+            .map_err(CrawlForgeError::Io)
+            .and_then(|file| {
+                let mut archive = Archive::new(BufWriter::new(file), format)?;
+                let archived = self.archive_all(entries, &mut archive)?;
+                archive.finish()?.flush().map_err(CrawlForgeError::Io)?;
+                Ok(archived)
+            });
+        match archived {
+            Ok(archived) => {
+                fs::rename(&part, path).map_err(CrawlForgeError::Io)?;
+                Ok(archived)
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&part);
+                Err(err)
+            }
+        }
+    }
+
+    /// Calls f for each entry, counting how many it returns true for, and
+    /// recording failures if keep_going
+    fn each<F>(&mut self, entries: &[Entry], mut f: F) -> Result<usize, CrawlForgeError>
+    where
+        F: FnMut(&Self, &Entry) -> Result<bool, CrawlForgeError>,
+    {
+        let mut downloaded = 0;
+        for entry in entries {
+            match f(self, entry) {
+                Ok(true) => downloaded += 1,
+                Ok(false) => (),
+                Err(err @ CrawlForgeError::AuthenticationFailed(_, _)) => return Err(err),
+                Err(err) if self.keep_going => {
+                    warn!("{}: {}", entry.url, err);
+                    self.failures.push((entry.url.clone(), err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(downloaded)
+    }
+
+    /// Downloads an entry, unless a file of the expected size is already
+    /// there. Returns whether it was downloaded.
+    pub fn download(&self, entry: &Entry) -> Result<bool, CrawlForgeError> {
+        let path = local_path(&self.dir, &entry.path)?;
+        if let (Ok(meta), Some(size)) = (fs::metadata(&path), entry.size) {
+            if meta.len() == size {
+                return Ok(false);
+            }
+        }
+
+        let (head, mut resp) = open_raw(&self.client, entry, self.lfs)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(CrawlForgeError::Io)?;
+        }
+        write_file(&path, &head, &mut resp)?;
+        Ok(true)
+    }
+}
+
+/// Writes head and then the rest of body to path. They're written to a .part
+/// file first, which is removed if anything fails, so a partial download is
+/// never mistaken for the file or left behind.
+fn write_file(path: &Path, head: &[u8], body: &mut dyn Read) -> Result<(), CrawlForgeError> {
+    let part = part_path(path);
+    let written = File::create(&part)
+        .and_then(|mut file| {
+            file.write_all(head)?;
+            io::copy(body, &mut file)?;
+            fs::rename(&part, path)
+        })
+        .map_err(CrawlForgeError::Io);
+    if written.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    written
+}
+
+/// Returns the path to write a file to before it's complete
+fn part_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!(
+        "{}.part",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ))
+}
+
+/// GETs the raw file of entry into out, returning its size
+fn copy_raw<W: Write>(
+    client: &Client,
+    entry: &Entry,
+    lfs: LfsMode,
+    out: &mut W,
+) -> Result<u64, CrawlForgeError> {
+    let (head, mut resp) = open_raw(client, entry, lfs)?;
+    out.write_all(&head).map_err(CrawlForgeError::Io)?;
+    let rest = io::copy(&mut resp, out).map_err(CrawlForgeError::Io)?;
+    Ok(head.len() as u64 + rest)
+}
+
+/// GETs the raw file of entry, returning its contents
+pub fn fetch(client: &Client, entry: &Entry, lfs: LfsMode) -> Result<Vec<u8>, CrawlForgeError> {
+    let (mut contents, mut resp) = open_raw(client, entry, lfs)?;
+    resp.read_to_end(&mut contents)
+        .map_err(CrawlForgeError::Io)?;
+    Ok(contents)
+}
+
+/// GETs the raw file of entry, checking it isn't an HTML page, and handling
+/// Git LFS pointers according to lfs. Returns the start of the body, and the
+/// response to read the rest from. Reading a fetched LFS object fails at the
+/// end if it doesn't match its pointer.
+fn open_raw(
+    client: &Client,
+    entry: &Entry,
+    lfs: LfsMode,
+) -> Result<(Vec<u8>, Box<dyn Read>), CrawlForgeError> {
+    let resp = client.get(&entry.url)?;
+    let status = resp.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        return Err(CrawlForgeError::AuthenticationFailed(
+            entry.url.clone(),
+            status.to_string(),
+        ));
+    }
+    let mut resp = resp.error_for_status().map_err(CrawlForgeError::Reqwest)?;
+
+    let final_url = resp.url().clone();
+    let content_type = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let mut head = Vec::new();
+    (&mut resp)
+        .take(SNIFF_LEN)
+        .read_to_end(&mut head)
+        .map_err(CrawlForgeError::Io)?;
+    check_raw_content(entry, &final_url, content_type.as_deref(), &head)?;
+
+    let pointer = match parse_pointer(&head) {
+        Some(pointer) if lfs != LfsMode::Keep => pointer,
+        _ => return Ok((head, Box::new(resp))),
+    };
+    if lfs == LfsMode::Refuse {
+        return Err(CrawlForgeError::LfsPointer(entry.url.clone(), pointer.size));
+    }
+    let url = media_url(entry)
+        .ok_or_else(|| CrawlForgeError::UnsupportedCommand("Git LFS".to_string(), entry.forge))?;
+    // The object should never be a pointer itself
+    let object = Entry {
+        url,
+        ..entry.clone()
+    };
+    let (head, resp) = open_raw(client, &object, LfsMode::Refuse)?;
+    let resp = ObjectReader::new(resp, object.url, pointer, &head);
+    Ok((head, Box::new(resp)))
+}
+
+/// Fails if the response to a raw file URL is an HTML page instead, such as a
+/// login or error page. head is the start of the body.
+fn check_raw_content(
+    entry: &Entry,
+    final_url: &url::Url,
+    content_type: Option<&str>,
+    head: &[u8],
+) -> Result<(), CrawlForgeError> {
+    if is_html_path(&entry.path) {
+        return Ok(());
+    }
+    let html_type = content_type.is_some_and(|t| t.trim_start().starts_with("text/html"));
+    if !html_type && !looks_like_html(head) {
+        return Ok(());
+    }
+    let body = String::from_utf8_lossy(head);
+    check_login_page(entry.forge, &entry.url, final_url, &body)?;
+    Err(CrawlForgeError::HtmlInsteadOfFile(entry.url.clone()))
+}
+
+/// Returns where to save the file at an index path under dir, refusing paths
+/// which would escape it
+pub(crate) fn local_path(dir: &Path, path: &str) -> Result<PathBuf, CrawlForgeError> {
+    let relative = Path::new(path);
+    let safe = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if !safe || path.is_empty() {
+        return Err(CrawlForgeError::UnsafePath(path.to_string()));
+    }
+    Ok(dir.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::server::failing_server;
+    use crate::ForgeKind;
+
+    fn downloader(dir: &Path) -> Downloader {
+        let opt = ClientOpt::from_iter(&["crawlforge", "--retries", "0"]);
+        let client = Client::new(&opt, &failing_server()).unwrap();
+        Downloader::new(client, dir)
+    }
+
+    fn entry(path: &str, size: Option<u64>) -> Entry {
+        Entry {
+            path: path.to_string(),
+            url: failing_server().join("raw/").unwrap().join(path).unwrap(),
+            forge: ForgeKind::OpenGrok,
+            git_ref: None,
+            sha: None,
+            size,
+            size_rounded: false,
+            date: None,
+        }
+    }
+
+    #[test]
+    fn test_local_path() {
+        let dir = Path::new("mirror");
+        assert_eq!(
+            local_path(dir, "AGL/foofile").unwrap(),
+            Path::new("mirror/AGL/foofile")
+        );
+        assert!(local_path(dir, "../etc/passwd").is_err());
+        assert!(local_path(dir, "/etc/passwd").is_err());
+        assert!(local_path(dir, "").is_err());
+    }
+
+    #[test]
+    fn test_check_raw_content() {
+        let e = entry("foofile", None);
+        assert!(check_raw_content(&e, &e.url, Some("text/plain"), b"int main;").is_ok());
+
+        let error_page = b"<html><body>Error 500</body></html>";
+        assert!(matches!(
+            check_raw_content(&e, &e.url, Some("text/plain"), error_page),
+            Err(CrawlForgeError::HtmlInsteadOfFile(_))
+        ));
+        assert!(check_raw_content(&e, &e.url, Some("text/html; charset=UTF-8"), b"").is_err());
+
+        let login = br#"<!DOCTYPE html><form><input type="password"></form>"#;
+        assert!(matches!(
+            check_raw_content(&e, &e.url, None, login),
+            Err(CrawlForgeError::AuthenticationFailed(_, _))
+        ));
+
+        let page = entry("index.html", None);
+        assert!(check_raw_content(&page, &page.url, Some("text/html"), error_page).is_ok());
+    }
+
+    #[test]
+    fn test_download_skips_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("foofile"), "1234").unwrap();
+        let downloader = downloader(dir.path());
+        assert!(!downloader.download(&entry("foofile", Some(4))).unwrap());
+        assert!(downloader.download(&entry("foofile", Some(5))).is_err());
+    }
+
+    #[test]
+    fn test_write_file_failure() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foofile");
+        assert!(write_file(&path, b"head", &mut Failing).is_err());
+        assert!(!path.exists());
+        assert!(!part_path(&path).exists());
+
+        write_file(&path, b"head", &mut &b" and body"[..]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"head and body");
+        assert!(!part_path(&path).exists());
+    }
+
+    #[test]
+    fn test_download_keep_going() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut downloader = downloader(dir.path());
+        downloader.keep_going = true;
+        let entries = [entry("a", None), entry("b", None)];
+        assert_eq!(downloader.download_all(&entries).unwrap(), 0);
+        assert_eq!(downloader.failures().len(), 2);
+    }
+}
@@ -0,0 +1,375 @@
+use crate::{parse_size, CrawlForgeError, Entry, ForgeKind};
+use chrono::{DateTime, NaiveDate, Utc};
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
+use std::cell::Cell;
+
+/// How globs match paths: "*" doesn't match "/", so "**" is needed to match
+/// across directories, as the directory pruning assumes
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Decides which directories to crawl and which files to index, by matching
+/// their paths relative to the crawl root against globs and regexes.
+///
+/// A file is indexed if it, or a directory above it, matches an include (or
+/// there are no includes), and nothing matches an exclude. Directories are
+/// only crawled if they could contain an included file, so excluded subtrees
+/// are never fetched.
+#[derive(Debug, Default)]
+pub struct PathFilter {
+    /// Forge path of the crawl root, which paths are made relative to
+    base: String,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    include_regex: Vec<Regex>,
+    exclude_regex: Vec<Regex>,
+}
+
+impl PathFilter {
+    /// Creates a filter for paths under base, the forge path of the crawl root
+    pub fn new(
+        base: &str,
+        include: &[String],
+        exclude: &[String],
+        include_regex: &[String],
+        exclude_regex: &[String],
+    ) -> Result<Self, CrawlForgeError> {
+        let globs = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| {
+                    Pattern::new(p.trim_matches('/'))
+                        .map_err(|e| CrawlForgeError::ParseFilterError(format!("{}, {}", p, e)))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let regexes = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| {
+                    Regex::new(p)
+                        .map_err(|e| CrawlForgeError::ParseFilterError(format!("{}, {}", p, e)))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(PathFilter {
+            base: base.trim_matches('/').to_string(),
+            include: globs(include)?,
+            exclude: globs(exclude)?,
+            include_regex: regexes(include_regex)?,
+            exclude_regex: regexes(exclude_regex)?,
+        })
+    }
+
+    /// Returns path relative to the crawl root
+    fn relative<'a>(&self, path: &'a str) -> &'a str {
+        let path = path.trim_matches('/');
+        if self.base.is_empty() {
+            return path;
+        }
+        match path.strip_prefix(self.base.as_str()) {
+            Some("") => "",
+            Some(rest) if rest.starts_with('/') => &rest[1..],
+            _ => path,
+        }
+    }
+
+    /// Returns whether to crawl the directory at forge path
+    pub fn crawl_dir(&self, path: &str) -> bool {
+        let path = self.relative(path);
+        if path.is_empty() {
+            return true;
+        }
+        if self.is_excluded(path, true) {
+            return false;
+        }
+        self.include.is_empty()
+            || !self.include_regex.is_empty()
+            || self.include.iter().any(|p| could_match_under(p, path))
+    }
+
+    /// Returns whether to index the file at forge path
+    pub fn index_file(&self, path: &str) -> bool {
+        let path = self.relative(path);
+        if self.is_excluded(path, false) {
+            return false;
+        }
+        if self.include.is_empty() && self.include_regex.is_empty() {
+            return true;
+        }
+        ancestors(path).any(|p| self.include.iter().any(|g| glob_matches(g, p)))
+            || self.include_regex.iter().any(|r| r.is_match(path))
+    }
+
+    fn is_excluded(&self, path: &str, is_dir: bool) -> bool {
+        let regex_path = if is_dir {
+            format!("{}/", path)
+        } else {
+            path.to_string()
+        };
+        ancestors(path).any(|p| {
+            self.exclude
+                .iter()
+                .any(|g| glob_matches(g, p) || g.as_str().strip_suffix("/**") == Some(p))
+        }) || self.exclude_regex.iter().any(|r| r.is_match(&regex_path))
+    }
+}
+
+/// Decides which files to index by the size and date shown in their listing.
+/// Files whose listing doesn't show the size or date filtered on are skipped,
+/// and counted so the user can be told.
+#[derive(Debug, Default)]
+pub struct MetadataFilter {
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<DateTime<Utc>>,
+    older_than: Option<DateTime<Utc>>,
+    missing: Cell<usize>,
+}
+
+impl MetadataFilter {
+    /// Creates a filter from human readable sizes and dates, failing if the
+    /// forge's listings don't show the metadata needed
+    pub fn new(
+        forge: ForgeKind,
+        min_size: Option<&str>,
+        max_size: Option<&str>,
+        newer_than: Option<&str>,
+        older_than: Option<&str>,
+    ) -> Result<Self, CrawlForgeError> {
+        let size = |option: &str, s: Option<&str>| {
+            s.map(|s| {
+                if forge == ForgeKind::GitHub {
+                    return Err(CrawlForgeError::UnsupportedFilter(
+                        option.to_string(),
+                        forge,
+                    ));
+                }
+                parse_size(s).ok_or_else(|| {
+                    CrawlForgeError::ParseFilterError(format!("{} {}, invalid size", option, s))
+                })
+            })
+            .transpose()
+        };
+        let date = |option: &str, s: Option<&str>| {
+            s.map(|s| {
+                parse_date(s).ok_or_else(|| {
+                    CrawlForgeError::ParseFilterError(format!("{} {}, invalid date", option, s))
+                })
+            })
+            .transpose()
+        };
+        Ok(MetadataFilter {
+            min_size: size("--min-size", min_size)?,
+            max_size: size("--max-size", max_size)?,
+            newer_than: date("--newer-than", newer_than)?,
+            older_than: date("--older-than", older_than)?,
+            missing: Cell::new(0),
+        })
+    }
+
+    /// Returns whether to index entry
+    pub fn index_file(&self, entry: &Entry) -> bool {
+        let by_size = self.min_size.is_some() || self.max_size.is_some();
+        let by_date = self.newer_than.is_some() || self.older_than.is_some();
+        if (by_size && entry.size.is_none()) || (by_date && entry.date.is_none()) {
+            self.missing.set(self.missing.get() + 1);
+            return false;
+        }
+
+        let size_ok = entry.size.is_none_or(|size| {
+            self.min_size.is_none_or(|min| size >= min)
+                && self.max_size.is_none_or(|max| size <= max)
+        });
+        let date_ok = entry.date.is_none_or(|date| {
+            self.newer_than.is_none_or(|newer| date >= newer)
+                && self.older_than.is_none_or(|older| date < older)
+        });
+        size_ok && date_ok
+    }
+
+    /// Returns how many files were skipped because their listing didn't show
+    /// the size or date to filter on
+    pub fn missing(&self) -> usize {
+        self.missing.get()
+    }
+}
+
+/// Parses a date given on the command line, either "2020-01-31" (midnight
+/// UTC) or RFC 3339
+fn parse_date(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+        return Some(date.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|d| d.and_utc())
+}
+
+/// Returns "a", "a/b", "a/b/c" for "a/b/c"
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/')
+        .map(move |(i, _)| &path[..i])
+        .chain(std::iter::once(path))
+}
+
+/// Returns whether the glob matches the whole of path
+fn glob_matches(glob: &Pattern, path: &str) -> bool {
+    glob.matches_with(path, MATCH_OPTIONS)
+}
+
+/// Returns whether the glob could match dir or something below it
+fn could_match_under(glob: &Pattern, dir: &str) -> bool {
+    // Everything under a matching directory is included
+    if ancestors(dir).any(|p| glob_matches(glob, p)) {
+        return true;
+    }
+    let glob_parts: Vec<_> = glob.as_str().split('/').collect();
+    for (i, dir_part) in dir.split('/').enumerate() {
+        match glob_parts.get(i) {
+            Some(&"**") => return true,
+            Some(glob_part) => match Pattern::new(glob_part) {
+                Ok(p) if glob_matches(&p, dir_part) => (),
+                _ => return false,
+            },
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        PathFilter::new(
+            "AGL/metalayers",
+            &strings(include),
+            &strings(exclude),
+            &[],
+            &[],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_filter_none() {
+        let f = filter(&[], &[]);
+        assert!(f.crawl_dir("AGL/metalayers/poky"));
+        assert!(f.index_file("AGL/metalayers/foofile"));
+    }
+
+    #[test]
+    fn test_filter_glob() {
+        let f = filter(&["meta-agl*"], &["poky/**", "**/*.bin"]);
+        assert!(f.crawl_dir("AGL/metalayers"));
+        assert!(f.crawl_dir("AGL/metalayers/meta-agl"));
+        assert!(f.crawl_dir("AGL/metalayers/meta-agl-demo"));
+        assert!(f.crawl_dir("AGL/metalayers/meta-agl/conf"));
+        assert!(!f.crawl_dir("AGL/metalayers/poky"));
+        assert!(!f.crawl_dir("AGL/metalayers/meta-qt5"));
+
+        assert!(f.index_file("AGL/metalayers/meta-agl/conf/layer.conf"));
+        assert!(!f.index_file("AGL/metalayers/meta-agl/firmware.bin"));
+        assert!(!f.index_file("AGL/metalayers/foofile"));
+
+        // Without "**", the exclude only applies at the top
+        let f = filter(&[], &["*.bin"]);
+        assert!(!f.index_file("AGL/metalayers/firmware.bin"));
+        assert!(f.index_file("AGL/metalayers/meta-agl/firmware.bin"));
+    }
+
+    #[test]
+    fn test_filter_glob_nested_include() {
+        let f = filter(&["meta-agl/recipes-*/**/*.bb"], &[]);
+        assert!(f.crawl_dir("AGL/metalayers/meta-agl"));
+        assert!(f.crawl_dir("AGL/metalayers/meta-agl/recipes-core"));
+        assert!(f.crawl_dir("AGL/metalayers/meta-agl/recipes-core/a/b"));
+        assert!(!f.crawl_dir("AGL/metalayers/meta-agl/conf"));
+        assert!(f.index_file("AGL/metalayers/meta-agl/recipes-core/a/b.bb"));
+        assert!(!f.index_file("AGL/metalayers/meta-agl/recipes-core/a/b.inc"));
+    }
+
+    #[test]
+    fn test_filter_glob_separator() {
+        // "*" stays within a directory, so files and directories agree
+        let f = filter(&["src/*.c"], &[]);
+        assert!(f.index_file("AGL/metalayers/src/x.c"));
+        assert!(!f.crawl_dir("AGL/metalayers/src/sub"));
+        assert!(!f.index_file("AGL/metalayers/src/sub/x.c"));
+
+        let f = filter(&["src/**/*.c"], &[]);
+        assert!(f.crawl_dir("AGL/metalayers/src/sub"));
+        assert!(f.index_file("AGL/metalayers/src/sub/x.c"));
+    }
+
+    #[test]
+    fn test_filter_regex() {
+        let f = PathFilter::new(
+            "",
+            &[],
+            &[],
+            &[r"\.(bb|bbappend)$".to_string()],
+            &["^poky/".to_string()],
+        )
+        .unwrap();
+        assert!(!f.crawl_dir("poky"));
+        assert!(f.crawl_dir("meta-agl"));
+        assert!(f.index_file("meta-agl/foo.bb"));
+        assert!(!f.index_file("meta-agl/foo.conf"));
+    }
+
+    #[test]
+    fn test_filter_metadata() {
+        let f = MetadataFilter::new(
+            ForgeKind::OpenGrok,
+            Some("1K"),
+            Some("1M"),
+            Some("2020-01-01"),
+            Some("2021-01-01T00:00:00Z"),
+        )
+        .unwrap();
+        let entry = |size, date: &str| Entry {
+            path: "foofile".to_string(),
+            url: url::Url::parse("http://10.0.0.1:8080/raw/foofile").unwrap(),
+            forge: ForgeKind::OpenGrok,
+            git_ref: None,
+            sha: None,
+            size,
+            size_rounded: false,
+            date: parse_date(date),
+        };
+        assert!(f.index_file(&entry(Some(1024), "2020-06-01")));
+        assert!(!f.index_file(&entry(Some(1023), "2020-06-01")));
+        assert!(!f.index_file(&entry(Some(2 << 20), "2020-06-01")));
+        assert!(!f.index_file(&entry(Some(1024), "2019-12-31")));
+        assert!(!f.index_file(&entry(Some(1024), "2021-01-01")));
+        assert_eq!(f.missing(), 0);
+
+        // Files the filter can't be applied to are skipped and counted
+        assert!(!f.index_file(&entry(None, "2020-06-01")));
+        assert!(!f.index_file(&entry(Some(1024), "Jun 2020")));
+        assert_eq!(f.missing(), 2);
+
+        let f = MetadataFilter::default();
+        assert!(f.index_file(&entry(None, "")));
+        assert_eq!(f.missing(), 0);
+    }
+
+    #[test]
+    fn test_filter_metadata_unsupported() {
+        let f = MetadataFilter::new(ForgeKind::GitHub, None, Some("1M"), None, None);
+        assert!(matches!(f, Err(CrawlForgeError::UnsupportedFilter(_, _))));
+        let f = MetadataFilter::new(ForgeKind::GitHub, None, None, Some("2020-01-01"), None);
+        assert!(f.is_ok());
+        let f = MetadataFilter::new(ForgeKind::OpenGrok, None, Some("big"), None, None);
+        assert!(matches!(f, Err(CrawlForgeError::ParseFilterError(_))));
+    }
+}
@@ -0,0 +1,299 @@
+use crate::client::Client;
+use crate::download::fetch;
+use crate::lfs::LfsMode;
+use crate::{CrawlForgeError, Entry};
+use chrono::{DateTime, Utc};
+use log::warn;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+/// Who commits crawled files
+const COMMITTER: &str = "crawlforge <crawlforge@localhost>";
+
+/// A commit of a complete snapshot of the files, replacing everything in the
+/// previous commit on the branch
+pub struct Commit {
+    /// Branch name, e.g. "master"
+    pub branch: String,
+    /// Who made the change, "Name <email>" or just a name, if not crawlforge
+    pub author: Option<String>,
+    pub date: DateTime<Utc>,
+    pub message: String,
+    /// (path, blob mark) of each file
+    pub files: Vec<(String, usize)>,
+}
+
+/// Writes a git fast-import stream
+pub struct FastImport<W: Write> {
+    out: W,
+    next_mark: usize,
+}
+
+impl<W: Write> FastImport<W> {
+    pub fn new(out: W) -> Self {
+        FastImport { out, next_mark: 1 }
+    }
+
+    /// Writes a blob, returning the mark to refer to it by
+    pub fn blob(&mut self, contents: &[u8]) -> Result<usize, CrawlForgeError> {
+        let mark = self.mark();
+        write!(self.out, "blob\nmark :{}\ndata {}\n", mark, contents.len())
+            .and_then(|_| self.out.write_all(contents))
+            .and_then(|_| writeln!(self.out))
+            .map_err(CrawlForgeError::Io)?;
+        Ok(mark)
+    }
+
+    /// Writes a commit, returning its mark
+    pub fn commit(&mut self, commit: &Commit) -> Result<usize, CrawlForgeError> {
+        let mark = self.mark();
+        let when = commit.date.timestamp();
+        let mut s = format!("commit refs/heads/{}\nmark :{}\n", commit.branch, mark);
+        if let Some(author) = &commit.author {
+            s += &format!("author {} {} +0000\n", ident(author), when);
+        }
+        s += &format!(
+            "committer {} {} +0000\ndata {}\n{}\ndeleteall\n",
+            COMMITTER,
+            when,
+            commit.message.len(),
+            commit.message
+        );
+        for (path, blob) in &commit.files {
+            s += &format!("M 100644 :{} {}\n", blob, quote_path(path));
+        }
+        s.push('\n');
+        self.out
+            .write_all(s.as_bytes())
+            .map_err(CrawlForgeError::Io)?;
+        Ok(mark)
+    }
+
+    /// Ends the stream, returning the writer
+    pub fn finish(mut self) -> Result<W, CrawlForgeError> {
+        writeln!(self.out, "done").map_err(CrawlForgeError::Io)?;
+        self.out.flush().map_err(CrawlForgeError::Io)?;
+        Ok(self.out)
+    }
+
+    fn mark(&mut self) -> usize {
+        self.next_mark += 1;
+        self.next_mark - 1
+    }
+}
+
+/// What import_snapshot committed
+pub struct Snapshot {
+    /// Branch the commit is on
+    pub branch: String,
+    /// Files left out of the commit by keep_going, and why
+    pub failures: Vec<(url::Url, CrawlForgeError)>,
+}
+
+/// Fetches the files of entries and writes them to stream as a single commit,
+/// recording the source URL and ref in the message. The branch is named
+/// after the ref, unless the ref is a commit. With keep_going, files which
+/// fail to download are left out, rather than stopping the import.
+pub fn import_snapshot<W: Write>(
+    client: &Client,
+    entries: &[Entry],
+    source: &url::Url,
+    stream: &mut FastImport<W>,
+    keep_going: bool,
+) -> Result<Snapshot, CrawlForgeError> {
+    let git_ref = entries.iter().find_map(|e| e.git_ref.clone());
+    let mut files = Vec::new();
+    let mut failures = Vec::new();
+    for entry in entries {
+        // Pointers are what the repository holds, so commit them as they are
+        match fetch(client, entry, LfsMode::Keep) {
+            Ok(contents) => files.push((entry.path.clone(), stream.blob(&contents)?)),
+            Err(err @ CrawlForgeError::AuthenticationFailed(_, _)) => return Err(err),
+            Err(err) if keep_going => {
+                warn!("{}: {}", entry.url, err);
+                failures.push((entry.url.clone(), err));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let branch = git_ref
+        .clone()
+        .filter(|git_ref| !is_commit_sha(git_ref))
+        .unwrap_or_else(|| "master".to_string());
+    let mut message = format!("Import crawled files\n\nSource: {}\n", source);
+    if let Some(git_ref) = &git_ref {
+        message += &format!("Ref: {}\n", git_ref);
+    }
+    stream.commit(&Commit {
+        branch: branch.clone(),
+        author: None,
+        // The newest file date makes the commit the same for the same files
+        date: entries
+            .iter()
+            .filter_map(|e| e.date)
+            .max()
+            .unwrap_or_else(Utc::now),
+        message,
+        files,
+    })?;
+    Ok(Snapshot { branch, failures })
+}
+
+/// Returns whether a ref looks like a commit SHA, full or abbreviated as
+/// GitHub accepts in URLs, rather than a branch or tag name
+pub(crate) fn is_commit_sha(git_ref: &str) -> bool {
+    (7..=64).contains(&git_ref.len()) && git_ref.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Returns "Name <email>" for an author, which fast-import needs even if the
+/// forge only shows a name
+fn ident(author: &str) -> String {
+    let author = author.trim();
+    if author.ends_with('>') && author.contains('<') {
+        author.to_string()
+    } else {
+        format!("{} <>", author.replace(['<', '>', '\n'], ""))
+    }
+}
+
+/// Quotes a path for a fast-import M command, if it needs it
+fn quote_path(path: &str) -> String {
+    if !path.starts_with('"') && !path.contains(['\n', '\\']) {
+        return path.to_string();
+    }
+    let quoted = path
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", quoted)
+}
+
+/// A new git repository, being fed by git fast-import
+pub struct GitRepo {
+    dir: PathBuf,
+    fast_import: Child,
+}
+
+impl GitRepo {
+    /// Creates a repository in dir, which must not exist or be empty
+    pub fn init(dir: &Path) -> Result<Self, CrawlForgeError> {
+        let not_empty = fs::read_dir(dir).is_ok_and(|mut d| d.next().is_some());
+        if not_empty {
+            return Err(CrawlForgeError::GitFailed(format!(
+                "init, {} is not empty",
+                dir.display()
+            )));
+        }
+        git(Command::new("git").arg("init").arg("-q").arg(dir), "init")?;
+        let fast_import = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["fast-import", "--quiet"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(CrawlForgeError::Io)?;
+        Ok(GitRepo {
+            dir: dir.to_path_buf(),
+            fast_import,
+        })
+    }
+
+    /// Returns the stream to write to git fast-import
+    pub fn stdin(&mut self) -> &mut ChildStdin {
+        self.fast_import
+            .stdin
+            .as_mut()
+            .expect("fast-import stdin should be piped")
+    }
+
+    /// Waits for git fast-import to finish, then checks out branch
+    pub fn finish(mut self, branch: &str) -> Result<(), CrawlForgeError> {
+        drop(self.fast_import.stdin.take());
+        let status = self.fast_import.wait().map_err(CrawlForgeError::Io)?;
+        if !status.success() {
+            return Err(CrawlForgeError::GitFailed("fast-import".to_string()));
+        }
+        let head = format!("refs/heads/{}", branch);
+        git(
+            Command::new("git")
+                .arg("-C")
+                .arg(&self.dir)
+                .args(["symbolic-ref", "HEAD", &head]),
+            "symbolic-ref",
+        )?;
+        git(
+            Command::new("git")
+                .arg("-C")
+                .arg(&self.dir)
+                .args(["reset", "-q", "--hard"]),
+            "reset",
+        )
+    }
+}
+
+/// Runs a git command, failing unless it succeeds
+fn git(command: &mut Command, name: &str) -> Result<(), CrawlForgeError> {
+    let status = command.status().map_err(CrawlForgeError::Io)?;
+    if !status.success() {
+        return Err(CrawlForgeError::GitFailed(name.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_fast_import() {
+        let mut stream = FastImport::new(Vec::new());
+        let blob = stream.blob(b"/target\n").unwrap();
+        let commit = Commit {
+            branch: "master".to_string(),
+            author: None,
+            date: Utc.timestamp_opt(1592894148, 0).unwrap(),
+            message: "Import\n".to_string(),
+            files: vec![(".gitignore".to_string(), blob)],
+        };
+        assert_eq!(stream.commit(&commit).unwrap(), 2);
+        assert_eq!(
+            String::from_utf8(stream.finish().unwrap()).unwrap(),
+            concat!(
+                "blob\nmark :1\ndata 8\n/target\n\n",
+                "commit refs/heads/master\nmark :2\n",
+                "committer crawlforge <crawlforge@localhost> 1592894148 +0000\n",
+                "data 7\nImport\n\ndeleteall\n",
+                "M 100644 :1 .gitignore\n\n",
+                "done\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_ident() {
+        assert_eq!(ident("Tom <tom@x.org>"), "Tom <tom@x.org>");
+        assert_eq!(ident("jsmoeller"), "jsmoeller <>");
+    }
+
+    #[test]
+    fn test_is_commit_sha() {
+        assert!(is_commit_sha("6e0d8bd04a79a37b9b0b6a6d4b1b24e6b0a8e2a1"));
+        assert!(!is_commit_sha("master"));
+        assert!(!is_commit_sha("v1.0"));
+        assert!(is_commit_sha("abc1234"));
+        assert!(!is_commit_sha("abc123"));
+        assert!(!is_commit_sha("release"));
+    }
+
+    #[test]
+    fn test_quote_path() {
+        assert_eq!(quote_path("src/main.rs"), "src/main.rs");
+        assert_eq!(quote_path("with space"), "with space");
+        assert_eq!(quote_path("\"quoted\""), "\"\\\"quoted\\\"\"");
+        assert_eq!(quote_path("a\\b"), "\"a\\\\b\"");
+    }
+}
@@ -0,0 +1,501 @@
+use crate::client::{Client, ClientOpt};
+use crate::crawl::Crawler;
+use crate::download::fetch;
+use crate::git::{is_commit_sha, Commit, FastImport};
+use crate::lfs::LfsMode;
+use crate::output::{IndexWriter, OutputFormat};
+use crate::refs::{bad_url, github_api_url};
+use crate::robots::Robots;
+use crate::{check_login_page, CrawlForgeError, Entry, ForgeKind};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use log::warn;
+use percent_encoding::percent_decode_str;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use soup::{NodeExt, QueryBuilderExt, Soup};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// The most commits the GitHub API returns per page
+const PER_PAGE: usize = 100;
+
+#[derive(StructOpt, Debug)]
+pub struct HistoryOpt {
+    /// URL of the forge directory, e.g. https://github.com/tompreston/sup/tree/master/src
+    pub url: url::Url,
+
+    /// Type of git forge
+    #[structopt(short, long, env, default_value = "github")]
+    pub forge: ForgeKind,
+
+    /// How many of the latest revisions to import
+    #[structopt(long, default_value = "10")]
+    pub max_revisions: usize,
+
+    /// Commit the revisions to a new git repository in this directory, instead
+    /// of writing a git fast-import stream to stdout
+    #[structopt(long, parse(from_os_str))]
+    pub to_git: Option<PathBuf>,
+
+    /// Fetch pages which the forge's robots.txt disallows
+    #[structopt(long)]
+    pub ignore_robots: bool,
+
+    #[structopt(flatten)]
+    pub client: ClientOpt,
+}
+
+/// A commit, as shown by the forge
+#[derive(Clone, Debug, PartialEq)]
+pub struct Revision {
+    /// Commit SHA, or whatever OpenGrok's r= parameter takes
+    pub id: String,
+    /// "Name <email>", or just a name
+    pub author: Option<String>,
+    pub date: Option<DateTime<Utc>>,
+    pub message: String,
+}
+
+/// A commit as returned by the GitHub commits API
+#[derive(Deserialize)]
+struct ApiCommit {
+    sha: String,
+    commit: ApiCommitDetail,
+}
+
+#[derive(Deserialize)]
+struct ApiCommitDetail {
+    author: Option<ApiAuthor>,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct ApiAuthor {
+    name: String,
+    email: String,
+    date: DateTime<Utc>,
+}
+
+/// Returns up to max of the latest revisions changing the directory at url,
+/// newest first. OpenGrok's history page must be allowed by robots.
+pub fn list_revisions(
+    client: &Client,
+    forge: ForgeKind,
+    url: &url::Url,
+    max: usize,
+    robots: Option<&Robots>,
+) -> Result<Vec<Revision>, CrawlForgeError> {
+    match forge {
+        ForgeKind::GitHub => {
+            let (git_ref, path) = github_tree(url);
+            let mut u = github_api_url(url)?
+                .join("commits")
+                .map_err(|_| bad_url(url))?;
+            {
+                let mut query = u.query_pairs_mut();
+                if let Some(git_ref) = &git_ref {
+                    query.append_pair("sha", git_ref);
+                }
+                if !path.is_empty() {
+                    query.append_pair("path", &percent_decode_str(&path).decode_utf8_lossy());
+                }
+                query.append_pair("per_page", &max.min(PER_PAGE).to_string());
+            }
+            let mut revisions = Vec::new();
+            let mut next = Some(u);
+            while let Some(u) = next.filter(|_| revisions.len() < max) {
+                let (body, next_page) = client.get_text_page(&u)?;
+                revisions.extend(parse_github_commits(&body)?);
+                next = next_page;
+            }
+            revisions.truncate(max);
+            Ok(revisions)
+        }
+        ForgeKind::OpenGrok => {
+            let u = opengrok_history_url(url)?;
+            if robots.is_some_and(|r| !r.is_allowed(u.path())) {
+                return Err(CrawlForgeError::Disallowed(u));
+            }
+            let (final_url, body) = client.get_page(&u)?;
+            check_login_page(forge, &u, &final_url, &body)?;
+            let mut revisions = parse_opengrok_history(&body)?;
+            revisions.truncate(max);
+            Ok(revisions)
+        }
+    }
+}
+
+/// Crawls root at each revision, oldest first, writing a commit of its files
+/// to stream. Returns the branch committed to.
+///
+/// OpenGrok can only list the current directories, so each revision has the
+/// current files as they were then, without any since deleted.
+pub fn import_history<W: Write>(
+    client: Client,
+    forge: ForgeKind,
+    root: &url::Url,
+    robots: Option<Robots>,
+    revisions: &[Revision],
+    stream: &mut FastImport<W>,
+) -> Result<String, CrawlForgeError> {
+    let (git_ref, _) = github_tree(root);
+    let branch = match forge {
+        ForgeKind::GitHub => git_ref
+            .filter(|git_ref| !is_commit_sha(git_ref))
+            .unwrap_or_else(|| "master".to_string()),
+        ForgeKind::OpenGrok => "master".to_string(),
+    };
+    let mut client = client;
+    let mut current = None;
+    // GitHub listings give each file's blob SHA, so unchanged files are only
+    // fetched once
+    let mut blobs = HashMap::new();
+
+    for revision in revisions.iter().rev() {
+        let entries = match forge {
+            ForgeKind::GitHub => {
+                let (entries, c) = crawl(
+                    client,
+                    forge,
+                    &github_tree_url(root, &revision.id)?,
+                    &robots,
+                )?;
+                client = c;
+                entries
+            }
+            ForgeKind::OpenGrok => {
+                if current.is_none() {
+                    let (entries, c) = crawl(client, forge, root, &robots)?;
+                    client = c;
+                    current = Some(entries);
+                }
+                let mut entries = current.clone().unwrap_or_default();
+                for entry in &mut entries {
+                    entry.url.query_pairs_mut().append_pair("r", &revision.id);
+                }
+                entries
+            }
+        };
+        commit_revision(&client, &entries, revision, &branch, stream, &mut blobs)?;
+    }
+    Ok(branch)
+}
+
+/// Crawls root, returning its files and the client back
+fn crawl(
+    client: Client,
+    forge: ForgeKind,
+    root: &url::Url,
+    robots: &Option<Robots>,
+) -> Result<(Vec<Entry>, Client), CrawlForgeError> {
+    let mut crawler = Crawler::new(forge, client);
+    crawler.robots = robots.clone();
+    crawler.keep_entries = true;
+    let mut index = IndexWriter::new(io::sink(), OutputFormat::Plain);
+    crawler.crawl(root.clone(), &mut index)?;
+    let entries = crawler.entries().to_vec();
+    Ok((entries, crawler.into_client()))
+}
+
+/// Fetches the files of entries and writes them to stream as revision's commit.
+/// Files which didn't exist yet at the revision are left out.
+fn commit_revision<W: Write>(
+    client: &Client,
+    entries: &[Entry],
+    revision: &Revision,
+    branch: &str,
+    stream: &mut FastImport<W>,
+    blobs: &mut HashMap<String, usize>,
+) -> Result<(), CrawlForgeError> {
+    let mut files = Vec::new();
+    for entry in entries {
+        if let Some(mark) = entry.sha.as_ref().and_then(|sha| blobs.get(sha)) {
+            files.push((entry.path.clone(), *mark));
+            continue;
+        }
+        let contents = match fetch(client, entry, LfsMode::Keep) {
+            Ok(contents) => contents,
+            Err(CrawlForgeError::Reqwest(err)) if err.status() == Some(StatusCode::NOT_FOUND) => {
+                continue
+            }
+            // OpenGrok shows an error page for revisions it can't show
+            Err(err @ CrawlForgeError::HtmlInsteadOfFile(_)) => {
+                warn!("{}: {}, leaving it out of {}", entry.path, err, revision.id);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let mark = stream.blob(&contents)?;
+        if let Some(sha) = &entry.sha {
+            blobs.insert(sha.clone(), mark);
+        }
+        files.push((entry.path.clone(), mark));
+    }
+
+    let message = format!(
+        "{}\n\nRevision: {}\n",
+        revision.message.trim_end(),
+        revision.id
+    );
+    stream.commit(&Commit {
+        branch: branch.to_string(),
+        author: revision.author.clone(),
+        date: revision.date.unwrap_or_else(Utc::now),
+        message,
+        files,
+    })?;
+    Ok(())
+}
+
+/// Parses the GitHub commits API
+fn parse_github_commits(body: &str) -> Result<Vec<Revision>, CrawlForgeError> {
+    let commits: Vec<ApiCommit> = serde_json::from_str(body).map_err(CrawlForgeError::Json)?;
+    Ok(commits
+        .into_iter()
+        .map(|c| Revision {
+            id: c.sha,
+            author: c
+                .commit
+                .author
+                .as_ref()
+                .map(|a| format!("{} <{}>", a.name, a.email)),
+            date: c.commit.author.map(|a| a.date),
+            message: c.commit.message,
+        })
+        .collect())
+}
+
+/// Parses the revisions table of an OpenGrok history page, newest first
+fn parse_opengrok_history(body: &str) -> Result<Vec<Revision>, CrawlForgeError> {
+    let soup = Soup::new(body);
+    let table = soup
+        .tag("table")
+        .attr("id", "revisions")
+        .find()
+        .ok_or_else(|| CrawlForgeError::ListingNotFound("revisions".to_string()))?;
+    let base = url::Url::parse("http://localhost/").expect("static URL should be correct");
+
+    let revisions = table
+        .tag("tr")
+        .find_all()
+        .filter_map(|row| {
+            let cols: Vec<_> = row.tag("td").find_all().collect();
+            let href = cols.first()?.tag("a").find()?.get("href")?;
+            let id = base
+                .join(&href)
+                .ok()?
+                .query_pairs()
+                .find(|(k, _)| k == "r")
+                .map(|(_, v)| v.into_owned())?;
+            let date_col = cols
+                .iter()
+                .position(|c| parse_history_date(&c.text()).is_some());
+            let date = date_col.and_then(|i| parse_history_date(&cols[i].text()));
+            let author = date_col
+                .and_then(|i| cols.get(i + 1))
+                .map(|c| c.text().trim().to_string())
+                .filter(|a| !a.is_empty());
+            let comments = cols.last()?;
+            let message = comments
+                .tag("p")
+                .class("rev-message-full")
+                .find()
+                .or_else(|| comments.tag("p").class("rev-message-summary").find())
+                .map_or_else(|| comments.text(), |p| p.text());
+            Some(Revision {
+                id,
+                author,
+                date,
+                message: message.trim().to_string(),
+            })
+        })
+        .collect();
+    Ok(revisions)
+}
+
+/// Parses the dates OpenGrok shows, e.g. "04-Jun-2020" or "04-Jun-2020 10:11"
+fn parse_history_date(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    NaiveDateTime::parse_from_str(s, "%d-%b-%Y %H:%M")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%d-%b-%Y")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+        .map(|d| d.and_utc())
+}
+
+/// Returns the OpenGrok history page of an xref directory URL
+fn opengrok_history_url(url: &url::Url) -> Result<url::Url, CrawlForgeError> {
+    let path = url.path();
+    let i = path.find("/xref/").ok_or_else(|| bad_url(url))?;
+    let mut u = url.clone();
+    u.set_path(&format!(
+        "{}/history/{}",
+        &path[..i],
+        &path[i + "/xref/".len()..]
+    ));
+    u.set_query(None);
+    u.set_fragment(None);
+    Ok(u)
+}
+
+/// Splits a GitHub URL "/owner/repo/tree/ref/path" into the ref and the
+/// still percent-encoded path, which are absent for the repository root
+fn github_tree(url: &url::Url) -> (Option<String>, String) {
+    let segments: Vec<_> = url
+        .path_segments()
+        .map(|s| s.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+    match segments.as_slice() {
+        [_owner, _repo, "tree", git_ref, path @ ..] => (Some(git_ref.to_string()), path.join("/")),
+        _ => (None, String::new()),
+    }
+}
+
+/// Returns the URL of the directory at url, as of the commit sha
+fn github_tree_url(url: &url::Url, sha: &str) -> Result<url::Url, CrawlForgeError> {
+    let mut segments = url.path_segments().ok_or_else(|| bad_url(url))?;
+    let owner = segments.next().filter(|s| !s.is_empty());
+    let repo = segments.next().filter(|s| !s.is_empty());
+    let (owner, repo) = owner.zip(repo).ok_or_else(|| bad_url(url))?;
+    let (_, path) = github_tree(url);
+
+    let mut u = url.clone();
+    u.set_query(None);
+    u.set_fragment(None);
+    u.set_path(&format!("/{}/{}/tree/{}/{}", owner, repo, sha, path));
+    Ok(u)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::server::scripted_server;
+    use chrono::TimeZone;
+
+    const HISTORY_OPENGROK: &str = r#"<table class="src" id="revisions">
+<thead><tr><th>Revision</th><th>Compare</th><th>Date</th><th>Author</th><th>Comments</th></tr></thead>
+<tbody>
+<tr><td><a href="/xref/AGL/metalayers/meta-agl?r=4b3c2a1d" title="link to revision">4b3c2a1d</a></td>
+<td><input type="radio" name="r1"/><input type="radio" name="r2"/></td>
+<td>04-Jun-2020 10:11</td><td>Jan-Simon Moeller &lt;jsmoeller@linuxfoundation.org&gt;</td>
+<td><a name="4b3c2a1d"></a><p class="rev-message-summary">Update meta-agl</p><p class="rev-message-full rev-message-hidden">Update meta-agl
+
+Bump to the latest release.</p></td></tr>
+<tr><td><a href="/xref/AGL/metalayers/meta-agl?r=%2Fmeta-agl%401" title="link to revision">1</a></td>
+<td></td><td>01-May-2020</td><td>jsmoeller</td><td>Initial import</td></tr>
+</tbody></table>"#;
+
+    #[test]
+    fn test_commit_revision_skips_error_page() {
+        let page = "<!DOCTYPE html><html>Error: revision not found</html>";
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            page.len(),
+            page
+        );
+        let (base, server) = scripted_server(&[&response]);
+        let opt = ClientOpt::from_iter(&["crawlforge", "--retries", "0"]);
+        let client = Client::new(&opt, &base).unwrap();
+        let entry = Entry {
+            path: "meta-agl/a.c".to_string(),
+            url: base.join("raw/meta-agl/a.c?r=4b3c2a1d").unwrap(),
+            forge: ForgeKind::OpenGrok,
+            git_ref: None,
+            sha: None,
+            size: None,
+            size_rounded: false,
+            date: None,
+        };
+        let revision = Revision {
+            id: "4b3c2a1d".to_string(),
+            author: None,
+            date: None,
+            message: "Update".to_string(),
+        };
+        let mut stream = FastImport::new(Vec::new());
+        let mut blobs = HashMap::new();
+        commit_revision(
+            &client,
+            &[entry],
+            &revision,
+            "master",
+            &mut stream,
+            &mut blobs,
+        )
+        .unwrap();
+        server.join().unwrap();
+        let stream = String::from_utf8(stream.finish().unwrap()).unwrap();
+        assert!(stream.starts_with("commit refs/heads/master"));
+        assert!(!stream.contains("\nM "));
+    }
+
+    #[test]
+    fn test_parse_opengrok_history() {
+        let revisions = parse_opengrok_history(HISTORY_OPENGROK).unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(revisions[0].id, "4b3c2a1d");
+        assert_eq!(
+            revisions[0].author.as_deref(),
+            Some("Jan-Simon Moeller <jsmoeller@linuxfoundation.org>")
+        );
+        assert_eq!(
+            revisions[0].date,
+            Some(Utc.with_ymd_and_hms(2020, 6, 4, 10, 11, 0).unwrap())
+        );
+        assert_eq!(
+            revisions[0].message,
+            "Update meta-agl\n\nBump to the latest release."
+        );
+        assert_eq!(revisions[1].id, "/meta-agl@1");
+        assert_eq!(revisions[1].message, "Initial import");
+    }
+
+    #[test]
+    fn test_parse_github_commits() {
+        let body = r#"[{"sha":"2189dc2","commit":{"author":{"name":"Tom Preston","email":"tom@x.org","date":"2020-06-23T06:35:48Z"},"message":"Fix the build\n"}}]"#;
+        assert_eq!(
+            parse_github_commits(body).unwrap(),
+            vec![Revision {
+                id: "2189dc2".to_string(),
+                author: Some("Tom Preston <tom@x.org>".to_string()),
+                date: Some(Utc.with_ymd_and_hms(2020, 6, 23, 6, 35, 48).unwrap()),
+                message: "Fix the build\n".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_history_urls() {
+        let u = |s| url::Url::parse(s).unwrap();
+        assert_eq!(
+            opengrok_history_url(&u("http://10.0.0.1/source/xref/AGL/metalayers/"))
+                .unwrap()
+                .as_str(),
+            "http://10.0.0.1/source/history/AGL/metalayers/"
+        );
+        assert_eq!(
+            github_tree(&u(
+                "https://github.com/tompreston/sup/tree/master/src/a%20b"
+            )),
+            (Some("master".to_string()), "src/a%20b".to_string())
+        );
+        assert_eq!(
+            github_tree_url(
+                &u("https://github.com/tompreston/sup/tree/master/src"),
+                "2189dc2"
+            )
+            .unwrap()
+            .as_str(),
+            "https://github.com/tompreston/sup/tree/2189dc2/src"
+        );
+        assert_eq!(
+            github_tree(&u("https://github.com/tompreston/sup")),
+            (None, String::new())
+        );
+    }
+}
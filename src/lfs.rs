@@ -0,0 +1,211 @@
+use crate::Entry;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+/// The first line of every Git LFS pointer file
+const VERSION: &str = "version https://git-lfs.github.com/spec/v1";
+
+/// Pointer files are small, so anything bigger is the real thing
+const MAX_POINTER_LEN: usize = 1024;
+
+/// What to do with Git LFS pointers served in place of files
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LfsMode {
+    /// Treat them as the files, as a git repository would
+    Keep,
+    /// Fail, since the file isn't what was wanted
+    Refuse,
+    /// Fetch the object they point to instead
+    Fetch,
+}
+
+/// A Git LFS pointer file, standing in for a large file kept elsewhere
+#[derive(Clone, Debug, PartialEq)]
+pub struct LfsPointer {
+    /// SHA-256 of the object
+    pub oid: String,
+    /// Size of the object in bytes
+    pub size: u64,
+}
+
+/// Returns the pointer in contents, if it is a Git LFS pointer file
+///
+/// # Example
+/// ```
+/// # use crawlforge::lfs::parse_pointer;
+/// let pointer = "version https://git-lfs.github.com/spec/v1\n\
+///     oid sha256:4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393\n\
+///     size 12345\n";
+/// assert_eq!(parse_pointer(pointer.as_bytes()).map(|p| p.size), Some(12345));
+/// assert_eq!(parse_pointer(b"int main;"), None);
+/// ```
+pub fn parse_pointer(contents: &[u8]) -> Option<LfsPointer> {
+    if contents.len() > MAX_POINTER_LEN {
+        return None;
+    }
+    let text = std::str::from_utf8(contents).ok()?;
+    let mut lines = text.lines();
+    if lines.next()? != VERSION {
+        return None;
+    }
+    let (mut oid, mut size) = (None, None);
+    for line in lines {
+        if let Some(hash) = line.strip_prefix("oid sha256:") {
+            oid = Some(hash.to_string());
+        } else if let Some(s) = line.strip_prefix("size ") {
+            size = s.parse().ok();
+        }
+    }
+    let oid = oid.filter(|o| o.len() == 64 && o.chars().all(|c| c.is_ascii_hexdigit()))?;
+    Some(LfsPointer { oid, size: size? })
+}
+
+/// Returns the pointer file git-lfs would commit for an object with contents
+pub fn pointer_for(contents: &[u8]) -> String {
+    pointer(&hex(&Sha256::digest(contents)), contents.len() as u64)
+}
+
+/// Returns the pointer file for an object with SHA-256 oid and size
+pub fn pointer(oid: &str, size: u64) -> String {
+    format!("{}\noid sha256:{}\nsize {}\n", VERSION, oid, size)
+}
+
+/// Formats a digest in lower case hex, as in pointer oids
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reads the object a pointer points to, failing at the end of it unless its
+/// SHA-256 and size match the pointer
+pub struct ObjectReader<R> {
+    inner: R,
+    url: url::Url,
+    pointer: LfsPointer,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> ObjectReader<R> {
+    /// Reads the object at url from inner, after head has already been read
+    pub fn new(inner: R, url: url::Url, pointer: LfsPointer, head: &[u8]) -> Self {
+        ObjectReader {
+            inner,
+            url,
+            pointer,
+            hasher: Sha256::new_with_prefix(head),
+            size: head.len() as u64,
+        }
+    }
+
+    fn check(&self) -> io::Result<()> {
+        let oid = hex(&self.hasher.clone().finalize());
+        if oid == self.pointer.oid.to_ascii_lowercase() && self.size == self.pointer.size {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Git LFS object {} is sha256:{} of {} bytes, but its pointer says sha256:{} of {} bytes",
+                self.url, oid, self.size, self.pointer.oid, self.pointer.size
+            ),
+        ))
+    }
+}
+
+impl<R: Read> Read for ObjectReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        if read == 0 && !buf.is_empty() {
+            self.check()?;
+        }
+        Ok(read)
+    }
+}
+
+/// Returns the URL GitHub serves the object of an LFS tracked file from, for
+/// a raw.githubusercontent.com entry
+pub fn media_url(entry: &Entry) -> Option<url::Url> {
+    if entry.url.host_str() != Some("raw.githubusercontent.com") {
+        return None;
+    }
+    let mut url = entry.url.clone();
+    url.set_host(Some("media.githubusercontent.com")).ok()?;
+    url.set_path(&format!("/media{}", entry.url.path()));
+    Some(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ForgeKind;
+
+    const POINTER: &str = "version https://git-lfs.github.com/spec/v1
+oid sha256:4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393
+size 12345
+";
+
+    #[test]
+    fn test_parse_pointer() {
+        assert_eq!(
+            parse_pointer(POINTER.as_bytes()),
+            Some(LfsPointer {
+                oid: "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393".to_string(),
+                size: 12345,
+            })
+        );
+        let no_oid = POINTER.replace("oid sha256:", "oid md5:");
+        assert_eq!(parse_pointer(no_oid.as_bytes()), None);
+        let long = POINTER.to_string() + &"x".repeat(MAX_POINTER_LEN);
+        assert_eq!(parse_pointer(long.as_bytes()), None);
+    }
+
+    #[test]
+    fn test_pointer_for() {
+        let pointer = pointer_for(b"hello\n");
+        assert_eq!(
+            parse_pointer(pointer.as_bytes()),
+            Some(LfsPointer {
+                oid: "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03".to_string(),
+                size: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn test_object_reader() {
+        let url = url::Url::parse("https://media.githubusercontent.com/media/o/r/m/a").unwrap();
+        let pointer = parse_pointer(pointer_for(b"hello\n").as_bytes()).unwrap();
+        let read = |head: &[u8], rest: &[u8]| {
+            let mut object = ObjectReader::new(rest, url.clone(), pointer.clone(), head);
+            io::copy(&mut object, &mut io::sink())
+        };
+        assert_eq!(read(b"hel", b"lo\n").unwrap(), 3);
+        assert!(read(b"hel", b"lo!").is_err());
+        assert!(read(b"hello\n", b"\n").is_err());
+    }
+
+    #[test]
+    fn test_media_url() {
+        let entry = |url: &str| Entry {
+            path: "fw/image.bin".to_string(),
+            url: url::Url::parse(url).unwrap(),
+            forge: ForgeKind::GitHub,
+            git_ref: Some("master".to_string()),
+            sha: None,
+            size: None,
+            size_rounded: false,
+            date: None,
+        };
+        assert_eq!(
+            media_url(&entry(
+                "https://raw.githubusercontent.com/o/r/master/fw/image.bin"
+            ))
+            .unwrap()
+            .as_str(),
+            "https://media.githubusercontent.com/media/o/r/master/fw/image.bin"
+        );
+        assert_eq!(media_url(&entry("http://10.0.0.1/raw/fw/image.bin")), None);
+    }
+}
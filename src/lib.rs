@@ -1,18 +1,196 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
 use soup::{NodeExt, QueryBuilderExt, Soup};
-use std::path::Path;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use structopt::StructOpt;
 use thiserror::Error;
 
+pub mod archive;
+pub mod auth;
+pub mod client;
+pub mod cookies;
+pub mod crawl;
+pub mod diff;
+pub mod download;
+pub mod filter;
+pub mod git;
+pub mod history;
+pub mod lfs;
+pub mod ls;
+pub mod output;
+pub mod refs;
+pub mod robots;
+pub mod since;
+pub mod state;
+pub mod throttle;
+pub mod verify;
+
+use client::ClientOpt;
+use diff::DiffOpt;
+use download::DownloadOpt;
+use history::HistoryOpt;
+use ls::LsOpt;
+use output::OutputFormat;
+use refs::RefsOpt;
+use verify::VerifyOpt;
+
+// Only ever parsed once, so the size of the crawl options doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt, Debug)]
 #[structopt(about = "Crawl a git-forge")]
-pub struct CrawlForgeOpt {
+pub enum CrawlForgeOpt {
+    /// Crawl a forge and print an index of its raw file URLs
+    Crawl(CrawlOpt),
+
+    /// Download the files in a --format jsonl index
+    Download(DownloadOpt),
+
+    /// Compare two --format jsonl indexes
+    Diff(DiffOpt),
+
+    /// List a single forge directory, without crawling
+    Ls(LsOpt),
+
+    /// List the branches and tags of a repository
+    Refs(RefsOpt),
+
+    /// Crawl the latest revisions of a directory into a git fast-import stream,
+    /// one commit per revision
+    ///
+    /// The OpenGrok history is approximate: OpenGrok only lists the current
+    /// directories, so each revision has the current files as they were then,
+    /// without any files deleted since.
+    History(HistoryOpt),
+
+    /// Check downloaded files against a --format jsonl index
+    Verify(VerifyOpt),
+}
+
+impl CrawlForgeOpt {
+    /// Parses the command line. "crawlforge [options] URL" still crawls, as it
+    /// did before there were subcommands.
+    pub fn from_args_or_crawl<I>(args: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        let mut args: Vec<std::ffi::OsString> = args.into_iter().map(Into::into).collect();
+        if args.get(1).is_some_and(|a| !Self::is_subcommand(a)) {
+            args.insert(1, "crawl".into());
+        }
+        Self::from_iter(args)
+    }
+
+    /// Returns whether arg names a subcommand, or asks for help or the version
+    pub fn is_subcommand(arg: &std::ffi::OsStr) -> bool {
+        // clap adds help itself, when parsing
+        let builtin = ["help", "-h", "--help", "-V", "--version"];
+        SUBCOMMANDS.iter().chain(&builtin).any(|s| arg == *s)
+    }
+}
+
+/// The names of the subcommands, which a test checks against clap's
+const SUBCOMMANDS: &[&str] = &[
+    "crawl", "download", "diff", "ls", "refs", "history", "verify",
+];
+
+#[derive(StructOpt, Debug)]
+pub struct CrawlOpt {
     /// URL of the forge to crawl
     pub url: url::Url,
 
     /// Type of git forge
     #[structopt(short, long, env, default_value = "github")]
     pub forge: ForgeKind,
+
+    /// Format of the index printed to stdout (plain, jsonl, aria2, curl)
+    #[structopt(long, default_value = "plain")]
+    pub format: OutputFormat,
+
+    /// Directory the downloader should save files under (aria2, curl)
+    #[structopt(long, parse(from_os_str))]
+    pub output_dir: Option<PathBuf>,
+
+    /// Download the files and commit them to a new git repository in this
+    /// directory, instead of printing the index. "-" writes a git fast-import
+    /// stream to stdout.
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["format", "output-dir", "since-index"]
+    )]
+    pub to_git: Option<PathBuf>,
+
+    /// File recording the crawl's progress; rerun with the same file to resume
+    #[structopt(long, parse(from_os_str))]
+    pub state: Option<PathBuf>,
+
+    /// Only index files matching this glob, relative to the crawl root. "*"
+    /// doesn't match "/", use "**" to match across directories
+    #[structopt(long)]
+    pub include: Vec<String>,
+
+    /// Don't crawl or index anything matching this glob, relative to the crawl
+    /// root. "*" doesn't match "/", use "**" to match across directories
+    #[structopt(long)]
+    pub exclude: Vec<String>,
+
+    /// Only index files matching this regex, relative to the crawl root
+    #[structopt(long)]
+    pub include_regex: Vec<String>,
+
+    /// Don't crawl or index anything matching this regex, relative to the crawl
+    /// root. Directories are matched with a trailing "/"
+    #[structopt(long)]
+    pub exclude_regex: Vec<String>,
+
+    /// Compare with this previous --format jsonl index. The jsonl index says
+    /// how each file changed, other formats only list files added or changed.
+    #[structopt(long, parse(from_os_str))]
+    pub since_index: Option<PathBuf>,
+
+    /// Only index files of at least this size, e.g. "10K"
+    #[structopt(long)]
+    pub min_size: Option<String>,
+
+    /// Only index files of at most this size, e.g. "100M"
+    #[structopt(long)]
+    pub max_size: Option<String>,
+
+    /// Only index files changed on or after this date, e.g. "2020-01-31"
+    #[structopt(long)]
+    pub newer_than: Option<String>,
+
+    /// Only index files changed before this date, e.g. "2020-01-31"
+    #[structopt(long)]
+    pub older_than: Option<String>,
+
+    /// Don't crawl directories more than this many levels below the URL
+    #[structopt(long)]
+    pub max_depth: Option<usize>,
+
+    /// Stop after indexing this many files
+    #[structopt(long)]
+    pub max_files: Option<usize>,
+
+    /// Stop after listing this many directories
+    #[structopt(long)]
+    pub max_dirs: Option<usize>,
+
+    /// Crawl directories which the forge's robots.txt disallows
+    #[structopt(long)]
+    pub ignore_robots: bool,
+
+    /// Carry on crawling after a directory fails, or with --to-git committing
+    /// after a file fails, and report failures at the end
+    #[structopt(short, long)]
+    pub keep_going: bool,
+
+    #[structopt(flatten)]
+    pub client: ClientOpt,
 }
 
 /// The errors which can happen when crawling a git forge
@@ -35,9 +213,83 @@ pub enum CrawlForgeError {
 
     #[error("Failed to get href from link element")]
     FailedToGetHref(String),
+
+    /// Unknown output format
+    #[error("Parse format error, {0}")]
+    ParseFormatError(String),
+
+    /// Malformed --include or --exclude pattern, or size or date filter
+    #[error("Parse filter error, {0}")]
+    ParseFilterError(String),
+
+    /// When a filter needs metadata which the forge's listings don't show
+    #[error("{0} is not supported for {1}, whose listings don't show it")]
+    UnsupportedFilter(String, ForgeKind),
+
+    /// When a subcommand needs something the forge doesn't have
+    #[error("{0} is not supported for {1}")]
+    UnsupportedCommand(String, ForgeKind),
+
+    /// When robots.txt disallows a page we can't do without
+    #[error("{0} is disallowed by robots.txt")]
+    Disallowed(url::Url),
+
+    /// When a raw file URL gives an HTML page, e.g. an error page
+    #[error("Got an HTML page instead of the raw file {0}")]
+    HtmlInsteadOfFile(url::Url),
+
+    /// When a raw file URL gives a Git LFS pointer, and objects aren't fetched
+    #[error("{0} is a Git LFS pointer to a {1} byte object, pass --lfs to fetch it")]
+    LfsPointer(url::Url, u64),
+
+    /// When a git command fails
+    #[error("git {0} failed")]
+    GitFailed(String),
+
+    /// When an index entry's path would be saved outside the output directory
+    #[error("Unsafe path in index, {0}")]
+    UnsafePath(String),
+
+    /// When the forge refuses a request with a 403, which below the root of
+    /// a crawl may just be a directory we aren't allowed into
+    #[error("Access forbidden to {0}; check your credentials or cookies")]
+    Forbidden(url::Url),
+
+    /// When a redirect loops, or goes on too long
+    #[error("Too many redirects from {0}")]
+    TooManyRedirects(url::Url),
+
+    /// When a numeric option is out of range, e.g. --delay inf
+    #[error("Invalid {0} {1}")]
+    InvalidOption(&'static str, String),
+
+    /// Malformed --header
+    #[error("Parse header error, {0}")]
+    ParseHeaderError(String),
+
+    /// When reading or writing a file fails
+    #[error("IO error, {0}")]
+    Io(std::io::Error),
+
+    /// When an entry cannot be serialized
+    #[error("JSON error, {0}")]
+    Json(serde_json::Error),
+
+    /// When the forge wants us to log in, e.g. because the session expired
+    #[error("Authentication failed for {0}, {1}; check your credentials or cookies")]
+    AuthenticationFailed(url::Url, String),
+
+    /// When --keep-going skipped directories which failed
+    #[error("{0} directories failed")]
+    DirectoriesFailed(usize),
+
+    /// When --keep-going skipped files which failed to download
+    #[error("{0} files failed")]
+    FilesFailed(usize),
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ForgeKind {
     GitHub,
     OpenGrok,
@@ -55,12 +307,69 @@ impl FromStr for ForgeKind {
     }
 }
 
+impl fmt::Display for ForgeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForgeKind::GitHub => write!(f, "github"),
+            ForgeKind::OpenGrok => write!(f, "opengrok"),
+        }
+    }
+}
+
 pub enum UrlKind {
     Directory,
     File,
     RawFile,
 }
 
+/// A raw file found in a forge directory listing, with whatever metadata the
+/// listing shows for it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Path relative to the repository (GitHub) or source root (OpenGrok)
+    pub path: String,
+
+    /// Raw file URL
+    pub url: url::Url,
+
+    pub forge: ForgeKind,
+
+    /// Branch, tag or commit, if the forge puts one in the URL
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+
+    /// Git blob SHA-1, if the listing shows it (GitHub)
+    pub sha: Option<String>,
+
+    /// Size in bytes
+    pub size: Option<u64>,
+
+    /// Whether the listing only showed the size rounded, e.g. "1.5 KiB"
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub size_rounded: bool,
+
+    /// Date of the last change
+    pub date: Option<DateTime<Utc>>,
+}
+
+/// The contents of one forge directory
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Listing {
+    /// URL of the directory
+    pub url: url::Url,
+
+    /// URLs of the subdirectories
+    pub dirs: Vec<url::Url>,
+
+    /// Raw files in the directory
+    pub entries: Vec<Entry>,
+
+    /// Date of the last change under each subdirectory, by forge path, if the
+    /// forge shows it (GitHub)
+    #[serde(default)]
+    pub dir_dates: HashMap<String, DateTime<Utc>>,
+}
+
 /// Returns the base_url. Copied from the Rust cookbook, although I added the err_url.
 /// https://rust-lang-nursery.github.io/rust-cookbook/web/url.html
 fn base_url(mut url: url::Url) -> Result<url::Url, CrawlForgeError> {
@@ -102,14 +411,46 @@ pub fn forge_base_url_raw(forge: ForgeKind, u: &url::Url) -> Result<url::Url, Cr
     Ok(base_url_raw)
 }
 
-/// Returns a list of Strings representing different UrlKinds
-fn parse_github(url_kind: UrlKind, body: &str) -> Result<Vec<String>, CrawlForgeError> {
-    let svg_class = match url_kind {
-        UrlKind::Directory => "octicon-file-directory",
-        UrlKind::File => "octicon-file",
-        UrlKind::RawFile => "octicon-file",
+/// Parses the human readable sizes shown by OpenGrok, e.g. "123" or "4.5 KiB".
+/// Units are treated as powers of 1024.
+///
+/// # Example
+/// ```
+/// # use crawlforge::parse_size;
+/// assert_eq!(parse_size("123"), Some(123));
+/// assert_eq!(parse_size("1.5 KiB"), Some(1536));
+/// assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+/// assert_eq!(parse_size("-"), None);
+/// ```
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim().replace(',', "");
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().ok()?;
+    let multiplier: u64 = match unit.trim().chars().next().map(|c| c.to_ascii_uppercase()) {
+        None | Some('B') => 1,
+        Some('K') => 1 << 10,
+        Some('M') => 1 << 20,
+        Some('G') => 1 << 30,
+        Some('T') => 1 << 40,
+        _ => return None,
     };
+    Some((num * multiplier as f64) as u64)
+}
+
+/// Returns whether a size from a listing is rounded, i.e. given in KiB or
+/// bigger units rather than bytes
+fn is_rounded_size(s: &str) -> bool {
+    s.contains(|c: char| "KMGTkmgt".contains(c))
+}
 
+/// Returns the js-navigation-items in a GitHub listing marked with svg_class
+fn github_nav_items(
+    body: &str,
+    svg_class: &str,
+) -> Result<Vec<impl NodeExt + QueryBuilderExt>, CrawlForgeError> {
     // Find the div repository-content, which contains the main repo links
     let rcontent_class = "repository-content";
     let rcontent = Soup::new(body)
@@ -123,13 +464,12 @@ fn parse_github(url_kind: UrlKind, body: &str) -> Result<Vec<String>, CrawlForge
     let nav_items: Vec<_> = rcontent
         .children()
         .filter(|child| child.is_element())
-        .map(|child| {
+        .flat_map(|child| {
             child
                 .tag("div")
                 .attr("class", "js-navigation-item")
                 .find_all()
         })
-        .flatten()
         .filter(|nav_item| {
             nav_item
                 .tag("svg")
@@ -139,8 +479,19 @@ fn parse_github(url_kind: UrlKind, body: &str) -> Result<Vec<String>, CrawlForge
         })
         .collect();
 
+    Ok(nav_items)
+}
+
+/// Returns a list of Strings representing different UrlKinds
+fn parse_github(url_kind: UrlKind, body: &str) -> Result<Vec<String>, CrawlForgeError> {
+    let svg_class = match url_kind {
+        UrlKind::Directory => "octicon-file-directory",
+        UrlKind::File => "octicon-file",
+        UrlKind::RawFile => "octicon-file",
+    };
+
     // Extract the urls from the nav_items
-    let urls: Vec<String> = nav_items
+    let urls: Vec<String> = github_nav_items(body, svg_class)?
         .iter()
         .filter_map(|n| n.tag("a").attr("class", "js-navigation-open").find())
         .filter_map(|a| a.get("href"))
@@ -194,6 +545,368 @@ fn parse_opengrok(
     Ok(urls)
 }
 
+/// Returns the path of a forge directory or file, given the path of its URL,
+/// relative to the repository (GitHub) or source root (OpenGrok)
+///
+/// # Example
+/// ```
+/// # use crawlforge::{forge_path, ForgeKind};
+/// assert_eq!(forge_path(ForgeKind::GitHub, "/tompreston/sup/tree/master/src/"), "src");
+/// assert_eq!(forge_path(ForgeKind::GitHub, "/tompreston/sup/"), "");
+/// assert_eq!(forge_path(ForgeKind::OpenGrok, "/xref/AGL/metalayers/poky/"), "AGL/metalayers/poky");
+/// ```
+pub fn forge_path(forge_kind: ForgeKind, url_path: &str) -> String {
+    let path = match forge_kind {
+        // /owner/repo/{tree,blob}/ref/path
+        ForgeKind::GitHub => url_path
+            .trim_start_matches('/')
+            .splitn(5, '/')
+            .nth(4)
+            .unwrap_or_default(),
+        ForgeKind::OpenGrok => match url_path.find("/xref/") {
+            Some(i) => &url_path[i + "/xref/".len()..],
+            None => url_path.trim_start_matches('/'),
+        },
+    };
+    decode(path.trim_end_matches('/'))
+}
+
+/// Splits a GitHub blob href "/owner/repo/blob/ref/path" into (ref, path).
+/// The href doesn't say where the ref ends, so a ref containing "/", e.g.
+/// "release/1.0", gives ref "release" and a path starting "1.0/".
+fn github_ref_path(href: &str) -> Option<(String, String)> {
+    let parts: Vec<_> = href.trim_start_matches('/').splitn(5, '/').collect();
+    match parts.as_slice() {
+        [_owner, _repo, _blob, git_ref, path] => Some((git_ref.to_string(), decode(path))),
+        _ => None,
+    }
+}
+
+/// Returns the raw file entries in a GitHub directory listing
+fn parse_github_entries(root: &url::Url, body: &str) -> Result<Vec<Entry>, CrawlForgeError> {
+    let base_url_raw = forge_base_url_raw(ForgeKind::GitHub, root)?;
+    let entries = github_nav_items(body, "octicon-file")?
+        .iter()
+        .filter_map(|item| {
+            let link = item.tag("a").attr("class", "js-navigation-open").find()?;
+            let href = link.get("href")?;
+            let (git_ref, path) = github_ref_path(&href)?;
+            // Link ids are "<md5 of the path>-<blob SHA-1>"
+            let sha = link
+                .get("id")
+                .and_then(|id| id.rsplit('-').next().map(str::to_string))
+                .filter(|sha| sha.len() == 40 && sha.chars().all(|c| c.is_ascii_hexdigit()));
+            let url = base_url_raw.join(&href.replacen("/blob/", "/", 1)).ok()?;
+            let date = item
+                .tag("time-ago")
+                .find()
+                .and_then(|t| t.get("datetime"))
+                .and_then(|d| DateTime::parse_from_rfc3339(&d).ok())
+                .map(|d| d.with_timezone(&Utc));
+            Some(Entry {
+                path,
+                url,
+                forge: ForgeKind::GitHub,
+                git_ref: Some(git_ref),
+                sha,
+                size: None,
+                size_rounded: false,
+                date,
+            })
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+/// Returns the date of the last commit under each subdirectory in a GitHub
+/// listing, by path
+fn parse_github_dir_dates(body: &str) -> Result<HashMap<String, DateTime<Utc>>, CrawlForgeError> {
+    let dates = github_nav_items(body, "octicon-file-directory")?
+        .iter()
+        .filter_map(|item| {
+            let href = item
+                .tag("a")
+                .attr("class", "js-navigation-open")
+                .find()?
+                .get("href")?;
+            let date = item.tag("time-ago").find()?.get("datetime")?;
+            let date = DateTime::parse_from_rfc3339(&date).ok()?;
+            Some((
+                forge_path(ForgeKind::GitHub, &href),
+                date.with_timezone(&Utc),
+            ))
+        })
+        .collect();
+    Ok(dates)
+}
+
+/// Returns the raw file entries in an OpenGrok directory listing
+fn parse_opengrok_entries(root: &url::Url, body: &str) -> Result<Vec<Entry>, CrawlForgeError> {
+    let base_url_raw = forge_base_url_raw(ForgeKind::OpenGrok, root)?;
+
+    let tbody_str = "tbody";
+    let tbody = Soup::new(body)
+        .tag(tbody_str)
+        .find()
+        .ok_or_else(|| CrawlForgeError::ListingNotFound(tbody_str.to_string()))?;
+
+    // Columns are: icon, name, history, date, size, lines, LOC
+    let entries = tbody
+        .children()
+        .filter_map(|row| {
+            let cols: Vec<_> = row.tag("td").find_all().collect();
+            let href = cols.get(1)?.tag("a").find()?.get("href")?;
+            if href == ".." || href.ends_with('/') {
+                return None;
+            }
+            let xref_path = Path::new(root.path())
+                .join(href)
+                .to_string_lossy()
+                .into_owned();
+            let url = base_url_raw
+                .join(&xref_path.replacen("/xref/", "/raw/", 1))
+                .ok()?;
+            let path = forge_path(ForgeKind::OpenGrok, &xref_path);
+            let date = cols.get(3).and_then(|c| parse_opengrok_date(&c.text()));
+            let size_text = cols.get(4).map(|c| c.text()).unwrap_or_default();
+            let size = parse_size(&size_text);
+            Some(Entry {
+                path,
+                url,
+                forge: ForgeKind::OpenGrok,
+                git_ref: None,
+                sha: None,
+                size,
+                size_rounded: size.is_some() && is_rounded_size(&size_text),
+                date,
+            })
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+/// Returns the date shown for each subdirectory in an OpenGrok listing, by
+/// forge path. It's the directory's mtime, so unlike GitHub's it misses
+/// changes deeper down, and isn't in Listing::dir_dates.
+pub(crate) fn parse_opengrok_dir_dates(
+    root: &url::Url,
+    body: &str,
+) -> Result<HashMap<String, DateTime<Utc>>, CrawlForgeError> {
+    let tbody_str = "tbody";
+    let tbody = Soup::new(body)
+        .tag(tbody_str)
+        .find()
+        .ok_or_else(|| CrawlForgeError::ListingNotFound(tbody_str.to_string()))?;
+    let dates = tbody
+        .children()
+        .filter_map(|row| {
+            let cols: Vec<_> = row.tag("td").find_all().collect();
+            let href = cols.get(1)?.tag("a").find()?.get("href")?;
+            if href == ".." || !href.ends_with('/') {
+                return None;
+            }
+            let xref_path = Path::new(root.path()).join(href);
+            let path = forge_path(ForgeKind::OpenGrok, &xref_path.to_string_lossy());
+            Some((path, parse_opengrok_date(&cols.get(3)?.text())?))
+        })
+        .collect();
+    Ok(dates)
+}
+
+/// Parses a date column of an OpenGrok listing, e.g. "04-Jun-2020"
+fn parse_opengrok_date(s: &str) -> Option<DateTime<Utc>> {
+    NaiveDate::parse_from_str(s.trim(), "%d-%b-%Y")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|d| Utc.from_utc_datetime(&d))
+}
+
+/// Percent-decodes a URL path
+fn decode(path: &str) -> String {
+    percent_encoding::percent_decode_str(path)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// Characters percent-encoded in a canonical URL path segment
+const PATH_SEGMENT: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Returns the canonical form of a URL, so that links to the same directory or
+/// file compare equal. Repeated and trailing slashes are removed, the path is
+/// consistently percent-encoded, the query is sorted and the fragment dropped.
+///
+/// # Example
+/// ```
+/// # use crawlforge::canonical_url;
+/// let u = |s| url::Url::parse(s).unwrap();
+/// assert_eq!(
+///     canonical_url(&u("http://Host/xref//a/%62/?y=2&x=1#l10")),
+///     canonical_url(&u("http://host:80/xref/a/b?x=1&y=2")),
+/// );
+/// ```
+pub fn canonical_url(u: &url::Url) -> url::Url {
+    let mut canonical = u.clone();
+    canonical.set_fragment(None);
+
+    let path: Vec<String> = u
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| percent_encoding::utf8_percent_encode(&decode(s), PATH_SEGMENT).to_string())
+        .collect();
+    canonical.set_path(&format!("/{}", path.join("/")));
+
+    let mut query: Vec<(String, String)> = u.query_pairs().into_owned().collect();
+    if query.is_empty() {
+        canonical.set_query(None);
+    } else {
+        query.sort();
+        canonical.query_pairs_mut().clear().extend_pairs(query);
+    }
+    canonical
+}
+
+/// Returns whether a URL path segment looks like a login page on the forge
+fn is_login_segment(forge_kind: ForgeKind, segment: &str) -> bool {
+    let segments: &[&str] = match forge_kind {
+        ForgeKind::GitHub => &["login", "session", "sessions", "sso"],
+        ForgeKind::OpenGrok => &[
+            "login",
+            "signin",
+            "sso",
+            "saml",
+            "saml2",
+            "oauth",
+            "oauth2",
+            "cas",
+            "auth",
+            "j_security_check",
+        ],
+    };
+    segments.contains(&segment.to_ascii_lowercase().as_str())
+}
+
+/// Returns whether the contents of a file look like an HTML page, e.g. a login
+/// or error page saved in place of a raw file
+///
+/// # Example
+/// ```
+/// # use crawlforge::looks_like_html;
+/// assert!(looks_like_html(b"\n<!DOCTYPE html>\n<html><body>Sign in</body></html>"));
+/// assert!(!looks_like_html(b"fn main() {}\n"));
+/// ```
+pub fn looks_like_html(contents: &[u8]) -> bool {
+    let contents = contents.strip_prefix(b"\xef\xbb\xbf").unwrap_or(contents);
+    let start: Vec<u8> = contents
+        .iter()
+        .skip_while(|b| b.is_ascii_whitespace())
+        .take(16)
+        .map(|b| b.to_ascii_lowercase())
+        .collect();
+    [&b"<!doctype html"[..], b"<html", b"<head"]
+        .iter()
+        .any(|tag| start.starts_with(tag))
+}
+
+/// Returns whether path is of a file which is meant to be HTML
+pub fn is_html_path(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    [".html", ".htm", ".xhtml"]
+        .iter()
+        .any(|ext| path.ends_with(ext))
+}
+
+/// Returns AuthenticationFailed if requesting url led to a login page rather
+/// than a directory listing. final_url is where any redirects ended up.
+pub fn check_login_page(
+    forge_kind: ForgeKind,
+    url: &url::Url,
+    final_url: &url::Url,
+    body: &str,
+) -> Result<(), CrawlForgeError> {
+    let fail = |reason: &str| {
+        Err(CrawlForgeError::AuthenticationFailed(
+            url.clone(),
+            reason.to_string(),
+        ))
+    };
+
+    // Forges redirect to mirrors and canonical hosts too, so only a redirect
+    // to a login looking path counts, wherever it's hosted
+    if final_url != url {
+        let login = final_url
+            .path_segments()
+            .is_some_and(|mut segs| segs.any(|s| is_login_segment(forge_kind, s)));
+        if login {
+            return fail(&format!(
+                "redirected to login page {}{}",
+                base_url(final_url.clone())?,
+                final_url.path().trim_start_matches('/')
+            ));
+        }
+    }
+
+    let password_input = Soup::new(body)
+        .tag("input")
+        .attr("type", "password")
+        .find()
+        .is_some();
+    if password_input {
+        return fail("got a login form instead of a directory listing");
+    }
+
+    Ok(())
+}
+
+/// Returns the raw file entries in a directory listing
+pub fn parse_forge_entries(
+    forge_kind: ForgeKind,
+    root: &url::Url,
+    body: &str,
+) -> Result<Vec<Entry>, CrawlForgeError> {
+    match forge_kind {
+        ForgeKind::GitHub => parse_github_entries(root, body),
+        ForgeKind::OpenGrok => parse_opengrok_entries(root, body),
+    }
+}
+
+/// Returns the subdirectories and raw file entries in a directory listing
+pub fn parse_listing(
+    forge_kind: ForgeKind,
+    root: &url::Url,
+    body: &str,
+) -> Result<Listing, CrawlForgeError> {
+    let dirs = parse_forge(forge_kind, UrlKind::Directory, root, body)?
+        .iter()
+        .filter_map(|dir_url| root.join(dir_url).ok())
+        .collect();
+    let entries = parse_forge_entries(forge_kind, root, body)?;
+    // OpenGrok shows directory mtimes, which miss changes deeper down
+    let dir_dates = match forge_kind {
+        ForgeKind::GitHub => parse_github_dir_dates(body)?,
+        ForgeKind::OpenGrok => HashMap::new(),
+    };
+    Ok(Listing {
+        url: root.clone(),
+        dirs,
+        entries,
+        dir_dates,
+    })
+}
+
 /// Returns a list of directory links
 pub fn parse_forge(
     forge_kind: ForgeKind,
@@ -210,8 +923,9 @@ pub fn parse_forge(
 #[cfg(test)]
 mod tests {
     use super::*;
-    mod data;
-    use data::{BODY_GITHUB, BODY_OPENGROK};
+    pub(crate) mod data;
+    pub(crate) mod server;
+    use data::{BODY_GITHUB, BODY_OPENGROK, BODY_OPENGROK_SIZES};
 
     #[test]
     fn test_parse_dirs_github() {
@@ -272,6 +986,203 @@ mod tests {
         assert_eq!(urls[0], "/raw/AGL/metalayers/foofile");
     }
 
+    #[test]
+    fn test_parse_entries_github() {
+        let root = url::Url::parse("https://github.com/tompreston/sup/").unwrap();
+        let entries = parse_github_entries(&root, BODY_GITHUB).unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[4].path, "README.md");
+        assert_eq!(
+            entries[4].url.as_str(),
+            "https://raw.githubusercontent.com/tompreston/sup/master/README.md"
+        );
+        assert_eq!(entries[4].git_ref.as_deref(), Some("master"));
+        assert_eq!(entries[0].path, ".gitignore");
+        assert_eq!(
+            entries[0].sha.as_deref(),
+            Some("ea8c4bf7f35f6f77f75d92ad8ce8349f6e81ddba")
+        );
+        assert_eq!(entries[4].size, None);
+        assert_eq!(
+            entries[4].date.map(|d| d.to_rfc3339()).as_deref(),
+            Some("2020-06-23T14:15:48+00:00")
+        );
+    }
+
+    #[test]
+    fn test_parse_crawl() {
+        let opt =
+            CrawlForgeOpt::from_iter(&["crawlforge", "crawl", "-k", "--insecure", "http://x/"]);
+        assert!(matches!(opt, CrawlForgeOpt::Crawl(o) if o.keep_going && o.client.insecure));
+    }
+
+    #[test]
+    fn test_parse_crawl_to_git() {
+        let to_git = ["crawlforge", "crawl", "--to-git", "repo", "http://x/"];
+        assert!(CrawlForgeOpt::from_iter_safe(&to_git).is_ok());
+        for other in [
+            ["--format", "jsonl"],
+            ["--output-dir", "dir"],
+            ["--since-index", "old.jsonl"],
+        ] {
+            let args = [&to_git[..], &other[..]].concat();
+            assert!(CrawlForgeOpt::from_iter_safe(&args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_download() {
+        let opt = CrawlForgeOpt::from_iter(&["crawlforge", "download", "-k", "index.jsonl"]);
+        assert!(matches!(opt, CrawlForgeOpt::Download(o) if o.keep_going));
+        let opt = CrawlForgeOpt::from_iter(&["crawlforge", "download", "index.jsonl"]);
+        assert!(matches!(opt, CrawlForgeOpt::Download(o) if !o.keep_going));
+    }
+
+    #[test]
+    fn test_parse_ls() {
+        let opt = CrawlForgeOpt::from_iter(&["crawlforge", "ls", "--format", "json", "http://x/"]);
+        assert!(matches!(opt, CrawlForgeOpt::Ls(_)));
+    }
+
+    #[test]
+    fn test_parse_refs() {
+        let opt = CrawlForgeOpt::from_iter(&["crawlforge", "refs", "https://github.com/o/r/"]);
+        assert!(matches!(opt, CrawlForgeOpt::Refs(_)));
+    }
+
+    #[test]
+    fn test_parse_history() {
+        let opt = CrawlForgeOpt::from_iter(&[
+            "crawlforge",
+            "history",
+            "--max-revisions",
+            "3",
+            "https://github.com/o/r/",
+        ]);
+        assert!(matches!(opt, CrawlForgeOpt::History(o) if o.max_revisions == 3));
+    }
+
+    #[test]
+    fn test_parse_verify() {
+        let opt =
+            CrawlForgeOpt::from_iter(&["crawlforge", "verify", "-o", "mirror", "index.jsonl"]);
+        assert!(matches!(opt, CrawlForgeOpt::Verify(_)));
+    }
+
+    #[test]
+    fn test_parse_without_subcommand() {
+        let opt = CrawlForgeOpt::from_args_or_crawl(&["crawlforge", "-f", "opengrok", "http://x/"]);
+        assert!(matches!(opt, CrawlForgeOpt::Crawl(o) if o.forge == ForgeKind::OpenGrok));
+        let opt = CrawlForgeOpt::from_args_or_crawl(&["crawlforge", "diff", "a.jsonl", "b.jsonl"]);
+        assert!(matches!(opt, CrawlForgeOpt::Diff(_)));
+    }
+
+    #[test]
+    fn test_is_subcommand() {
+        assert!(!CrawlForgeOpt::is_subcommand(
+            "https://github.com/o/r/".as_ref()
+        ));
+        for arg in [
+            "crawl",
+            "download",
+            "history",
+            "verify",
+            "help",
+            "--version",
+        ] {
+            assert!(CrawlForgeOpt::is_subcommand(arg.as_ref()));
+        }
+    }
+
+    #[test]
+    fn test_subcommands_match_clap() {
+        let mut help = Vec::new();
+        CrawlForgeOpt::clap().write_help(&mut help).unwrap();
+        let help = String::from_utf8(help).unwrap();
+        let (_, listed) = help.split_once("SUBCOMMANDS:").unwrap();
+        let mut names: Vec<_> = listed
+            .lines()
+            // Wrapped descriptions are indented further than the names
+            .filter(|line| line.starts_with("    ") && !line[4..].starts_with(' '))
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|name| *name != "help")
+            .collect();
+        let mut expected = SUBCOMMANDS.to_vec();
+        names.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_parse_dir_dates_github() {
+        let dates = parse_github_dir_dates(BODY_GITHUB).unwrap();
+        assert_eq!(
+            dates.get("src").map(|d| d.to_rfc3339()).as_deref(),
+            Some("2020-06-23T14:15:48+00:00")
+        );
+        assert!(dates.contains_key(".github/workflows"));
+    }
+
+    #[test]
+    fn test_parse_entries_opengrok() {
+        let root = url::Url::parse("http://10.0.0.1:8080/xref/AGL/metalayers/").unwrap();
+        let entries = parse_opengrok_entries(&root, BODY_OPENGROK).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "AGL/metalayers/foofile");
+        assert_eq!(
+            entries[0].url.as_str(),
+            "http://10.0.0.1:8080/raw/AGL/metalayers/foofile"
+        );
+        assert_eq!(entries[0].git_ref, None);
+        assert_eq!(entries[0].size, None);
+        assert_eq!(
+            entries[0].date.map(|d| d.to_rfc3339()).as_deref(),
+            Some("2020-06-08T00:00:00+00:00")
+        );
+    }
+
+    #[test]
+    fn test_parse_entries_opengrok_sizes() {
+        let root = url::Url::parse("http://10.0.0.1:8080/xref/AGL/metalayers/").unwrap();
+        let entries = parse_opengrok_entries(&root, BODY_OPENGROK_SIZES).unwrap();
+        let sizes: Vec<_> = entries
+            .iter()
+            .map(|e| (e.path.as_str(), e.size, e.size_rounded))
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                ("AGL/metalayers/foofile", Some(1536), true),
+                ("AGL/metalayers/bar.c", Some(123), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_login_page() {
+        let u = url::Url::parse("https://github.com/tompreston/sup/").unwrap();
+        assert!(check_login_page(ForgeKind::GitHub, &u, &u, BODY_GITHUB).is_ok());
+
+        let login = url::Url::parse("https://github.com/login?return_to=%2Ftompreston").unwrap();
+        assert!(matches!(
+            check_login_page(ForgeKind::GitHub, &u, &login, ""),
+            Err(CrawlForgeError::AuthenticationFailed(_, _))
+        ));
+
+        let u = url::Url::parse("http://10.0.0.1:8080/xref/AGL/metalayers/").unwrap();
+        assert!(check_login_page(ForgeKind::OpenGrok, &u, &u, BODY_OPENGROK).is_ok());
+
+        let idp = url::Url::parse("https://sso.example.com/saml2/idp/SSOService").unwrap();
+        assert!(check_login_page(ForgeKind::OpenGrok, &u, &idp, "").is_err());
+
+        // Redirects to another host are fine, unless they look like a login
+        let mirror = url::Url::parse("https://mirror.example.com/xref/AGL/metalayers/").unwrap();
+        assert!(check_login_page(ForgeKind::OpenGrok, &u, &mirror, BODY_OPENGROK).is_ok());
+
+        let form = r#"<form method="post"><input type="password" name="j_password"></form>"#;
+        assert!(check_login_page(ForgeKind::OpenGrok, &u, &u, form).is_err());
+    }
+
     #[test]
     fn test_url_username_password() {
         let u =
@@ -0,0 +1,187 @@
+use crate::client::{Client, ClientOpt};
+use crate::{
+    check_login_page, forge_path, parse_listing, parse_opengrok_dir_dates, CrawlForgeError,
+    ForgeKind, Listing,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::io::Write;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct LsOpt {
+    /// URL of the forge directory to list
+    pub url: url::Url,
+
+    /// Type of git forge
+    #[structopt(short, long, env, default_value = "github")]
+    pub forge: ForgeKind,
+
+    /// Output format: table or json
+    #[structopt(long, default_value = "table")]
+    pub format: LsFormat,
+
+    #[structopt(flatten)]
+    pub client: ClientOpt,
+}
+
+/// The format of a single directory listing
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LsFormat {
+    /// Aligned columns, like ls -l
+    Table,
+    /// A JSON array of the rows
+    Json,
+}
+
+impl FromStr for LsFormat {
+    type Err = CrawlForgeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(LsFormat::Table),
+            "json" => Ok(LsFormat::Json),
+            _ => Err(CrawlForgeError::ParseFormatError(s.to_string())),
+        }
+    }
+}
+
+/// A subdirectory or file in a listing
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LsRow {
+    /// "dir" or "file"
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+
+    /// Name within the directory, ending in "/" for directories
+    pub name: String,
+
+    pub size: Option<u64>,
+    pub date: Option<DateTime<Utc>>,
+
+    /// Directory URL, or raw file URL
+    pub url: url::Url,
+}
+
+/// Fetches a single forge directory, without recursing
+pub fn list_dir(
+    client: &Client,
+    forge: ForgeKind,
+    dir: &url::Url,
+) -> Result<Vec<LsRow>, CrawlForgeError> {
+    let (final_url, body) = client.get_page(dir)?;
+    check_login_page(forge, dir, &final_url, &body)?;
+    Ok(rows(forge, &ls_listing(forge, dir, &body)?))
+}
+
+/// Parses a listing, with the directory dates OpenGrok shows, which crawls
+/// leave out since they miss changes deeper down
+fn ls_listing(forge: ForgeKind, dir: &url::Url, body: &str) -> Result<Listing, CrawlForgeError> {
+    let mut listing = parse_listing(forge, dir, body)?;
+    if forge == ForgeKind::OpenGrok {
+        listing.dir_dates = parse_opengrok_dir_dates(dir, body)?;
+    }
+    Ok(listing)
+}
+
+/// Returns the rows of a listing, directories first
+fn rows(forge: ForgeKind, listing: &Listing) -> Vec<LsRow> {
+    let name = |path: &str| path.rsplit('/').next().unwrap_or_default().to_string();
+    let dirs = listing.dirs.iter().map(|d| {
+        let path = forge_path(forge, d.path());
+        LsRow {
+            kind: "dir",
+            name: format!("{}/", name(&path)),
+            size: None,
+            date: listing.dir_dates.get(&path).copied(),
+            url: d.clone(),
+        }
+    });
+    let files = listing.entries.iter().map(|e| LsRow {
+        kind: "file",
+        name: name(&e.path),
+        size: e.size,
+        date: e.date,
+        url: e.url.clone(),
+    });
+    dirs.chain(files).collect()
+}
+
+/// Writes rows in format
+pub fn write_rows<W: Write>(
+    mut out: W,
+    rows: &[LsRow],
+    format: LsFormat,
+) -> Result<(), CrawlForgeError> {
+    match format {
+        LsFormat::Table => write_table(&mut out, rows).map_err(CrawlForgeError::Io),
+        LsFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, rows).map_err(CrawlForgeError::Json)?;
+            writeln!(out).map_err(CrawlForgeError::Io)
+        }
+    }
+}
+
+fn write_table<W: Write>(out: &mut W, rows: &[LsRow]) -> std::io::Result<()> {
+    let cells: Vec<[String; 5]> = rows
+        .iter()
+        .map(|r| {
+            [
+                r.kind.to_string(),
+                r.size.map_or_else(|| "-".to_string(), |s| s.to_string()),
+                r.date
+                    .map_or_else(|| "-".to_string(), |d| d.format("%Y-%m-%d").to_string()),
+                r.name.clone(),
+                r.url.to_string(),
+            ]
+        })
+        .collect();
+    let width = |i: usize| cells.iter().map(|c| c[i].len()).max().unwrap_or(0);
+    let (kind_w, size_w, date_w, name_w) = (width(0), width(1), width(2), width(3));
+    for c in &cells {
+        writeln!(
+            out,
+            "{:<kind_w$}  {:>size_w$}  {:<date_w$}  {:<name_w$}  {}",
+            c[0],
+            c[1],
+            c[2],
+            c[3],
+            c[4],
+            kind_w = kind_w,
+            size_w = size_w,
+            date_w = date_w,
+            name_w = name_w,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::data::BODY_OPENGROK_SIZES;
+
+    #[test]
+    fn test_ls_opengrok() {
+        let root = url::Url::parse("http://10.0.0.1:8080/xref/AGL/metalayers/").unwrap();
+        let listing = ls_listing(ForgeKind::OpenGrok, &root, BODY_OPENGROK_SIZES).unwrap();
+        let rows = rows(ForgeKind::OpenGrok, &listing);
+        let dir = rows.iter().find(|r| r.kind == "dir").unwrap();
+        assert_eq!(dir.name, "sub/");
+        assert_eq!(
+            dir.date.map(|d| d.to_rfc3339()).as_deref(),
+            Some("2020-06-05T00:00:00+00:00")
+        );
+        let file = rows.iter().find(|r| r.name == "foofile").unwrap();
+        assert_eq!(file.size, Some(1536));
+
+        let mut out = Vec::new();
+        write_rows(&mut out, &rows, LsFormat::Table).unwrap();
+        let table = String::from_utf8(out).unwrap();
+        assert_eq!(table.lines().count(), rows.len());
+        let url_column = |line: &str| line.find("http://");
+        let columns: Vec<_> = table.lines().map(url_column).collect();
+        assert!(columns.windows(2).all(|w| w[0] == w[1]));
+    }
+}
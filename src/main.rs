@@ -1,35 +1,351 @@
-use crawlforge::{
-    forge_base_url_raw, parse_forge, CrawlForgeError, CrawlForgeOpt, ForgeKind, UrlKind,
-};
-use structopt::StructOpt;
-
-fn crawl_forge_dir(forge: ForgeKind, root: url::Url) -> Result<(), CrawlForgeError> {
-    let body = reqwest::blocking::get(root.clone())
-        .map_err(CrawlForgeError::Reqwest)?
-        .text()
-        .map_err(CrawlForgeError::Reqwest)?;
-
-    // Print the raw files
-    let base_url_raw = forge_base_url_raw(forge, &root)?;
-    parse_forge(forge, UrlKind::RawFile, &root, body.as_str())?
-        .iter()
-        .filter_map(|raw_file_url| base_url_raw.join(raw_file_url).ok())
-        .for_each(|url| println!("{}", url));
+use crawlforge::auth::strip_credentials;
+use crawlforge::client::Client;
+use crawlforge::crawl::Crawler;
+use crawlforge::diff::{DiffOpt, IndexDiff};
+use crawlforge::download::{DownloadOpt, Downloader};
+use crawlforge::filter::{MetadataFilter, PathFilter};
+use crawlforge::git::{import_snapshot, FastImport, GitRepo};
+use crawlforge::history::{import_history, list_revisions, HistoryOpt};
+use crawlforge::lfs::LfsMode;
+use crawlforge::ls::{list_dir, write_rows, LsOpt};
+use crawlforge::output::{is_credential_header, parse_jsonl_index, IndexWriter, OutputFormat};
+use crawlforge::refs::{list_refs, RefsOpt};
+use crawlforge::robots::Robots;
+use crawlforge::since::{Change, PreviousIndex};
+use crawlforge::state::CrawlState;
+use crawlforge::verify::{verify_entry, VerifyOpt};
+use crawlforge::{forge_path, CrawlForgeError, CrawlForgeOpt, CrawlOpt};
+use std::fs;
+use std::io::{self, Write};
 
-    // Recurse into dirs
-    let dir_urls: Vec<_> = parse_forge(forge, UrlKind::Directory, &root, body.as_str())?
+fn crawl(opt: CrawlOpt) -> Result<(), CrawlForgeError> {
+    // A git repository replaces the index
+    let out: Box<dyn Write> = match opt.to_git {
+        Some(_) => Box::new(io::sink()),
+        None => Box::new(io::stdout()),
+    };
+    let mut index = IndexWriter::new(out, opt.format);
+    index.dir = opt.output_dir;
+    // Credentials sent as headers stay out of the index, like those in URLs
+    let (secret, headers): (Vec<_>, Vec<_>) = opt
+        .client
+        .headers
         .iter()
-        .filter_map(|dir_url| root.join(dir_url).ok())
-        .collect();
-    for d in dir_urls {
-        crawl_forge_dir(forge, d)?;
+        .cloned()
+        .partition(|h| is_credential_header(h));
+    if !secret.is_empty() && matches!(opt.format, OutputFormat::Aria2 | OutputFormat::Curl) {
+        eprintln!(
+            "crawlforge: warning: Authorization and Cookie headers aren't written to the index, pass them to the downloader directly"
+        );
+    }
+    index.headers = headers;
+    index.cookies = opt
+        .client
+        .cookie_jar
+        .as_ref()
+        .or(opt.client.cookies.as_ref())
+        .cloned();
+
+    // Authenticate through the client, so credentials don't end up in the index
+    let mut client = Client::new(&opt.client, &opt.url)?;
+    let root = strip_credentials(&opt.url);
+
+    let robots = if opt.ignore_robots {
+        None
+    } else {
+        let robots = Robots::fetch(&client, &root)?;
+        if let Some(delay) = robots.crawl_delay {
+            client.set_min_delay(delay);
+        }
+        Some(robots)
+    };
+
+    let mut crawler = Crawler::new(opt.forge, client);
+    crawler.robots = robots;
+    crawler.filter = PathFilter::new(
+        &forge_path(opt.forge, root.path()),
+        &opt.include,
+        &opt.exclude,
+        &opt.include_regex,
+        &opt.exclude_regex,
+    )?;
+    crawler.metadata_filter = MetadataFilter::new(
+        opt.forge,
+        opt.min_size.as_deref(),
+        opt.max_size.as_deref(),
+        opt.newer_than.as_deref(),
+        opt.older_than.as_deref(),
+    )?;
+    crawler.keep_going = opt.keep_going;
+    crawler.keep_entries = opt.to_git.is_some();
+    crawler.max_depth = opt.max_depth;
+    crawler.max_files = opt.max_files;
+    crawler.max_dirs = opt.max_dirs;
+    if let Some(path) = &opt.since_index {
+        crawler.since = Some(PreviousIndex::load(path)?);
+    }
+    if let Some(path) = &opt.state {
+        crawler.state = Some(CrawlState::open(path)?);
+    }
+
+    index.write_preamble()?;
+    crawler.crawl(root, &mut index)?;
+    if let Some(option) = crawler.stopped_by() {
+        eprintln!("crawlforge: warning: stopped early, reached {}", option);
+    }
+    let missing = crawler.metadata_filter.missing();
+    if missing > 0 {
+        eprintln!(
+            "crawlforge: warning: skipped {} files whose listing didn't show the size or date to filter on",
+            missing
+        );
+    }
+
+    if let Some(since) = &crawler.since {
+        let removed = since.removed();
+        for entry in &removed {
+            index.write_change(entry, Change::Removed)?;
+        }
+        // Keep what we didn't get to for next time
+        for entry in since.not_visited() {
+            index.write_change(entry, Change::Unchanged)?;
+        }
+        eprintln!(
+            "crawlforge: {} added, {} modified, {} removed since the previous index",
+            since.added,
+            since.modified,
+            removed.len()
+        );
+    }
+
+    let mut file_failures = Vec::new();
+    if let Some(dir) = &opt.to_git {
+        let source = strip_credentials(&opt.url);
+        let entries = crawler.entries();
+        if dir.as_os_str() == "-" {
+            let mut stream = FastImport::new(io::stdout());
+            let snapshot = import_snapshot(
+                crawler.client(),
+                entries,
+                &source,
+                &mut stream,
+                opt.keep_going,
+            )?;
+            stream.finish()?;
+            file_failures = snapshot.failures;
+        } else {
+            let mut repo = GitRepo::init(dir)?;
+            let mut stream = FastImport::new(repo.stdin());
+            let snapshot = import_snapshot(
+                crawler.client(),
+                entries,
+                &source,
+                &mut stream,
+                opt.keep_going,
+            )?;
+            stream.finish()?;
+            repo.finish(&snapshot.branch)?;
+            eprintln!(
+                "crawlforge: committed {} files to {}",
+                entries.len() - snapshot.failures.len(),
+                dir.display()
+            );
+            file_failures = snapshot.failures;
+        }
+    }
+
+    // The curl config already names the cookie file, the others need telling
+    let downloader = match opt.format {
+        OutputFormat::Plain => Some("wget"),
+        OutputFormat::Aria2 => Some("aria2c"),
+        _ => None,
+    };
+    if let (None, Some(downloader), Some(cookies)) = (&opt.to_git, downloader, &index.cookies) {
+        eprintln!(
+            "crawlforge: download with {} --load-cookies {}",
+            downloader,
+            cookies.display()
+        );
+    }
+
+    crawler.client().save_cookies()?;
+    let failures = crawler.failures();
+    if !failures.is_empty() {
+        eprintln!("crawlforge: failed directories:");
+        for (url, err) in failures {
+            eprintln!("  {}: {}", url, err);
+        }
+    }
+    if !file_failures.is_empty() {
+        eprintln!("crawlforge: failed files:");
+        for (url, err) in &file_failures {
+            eprintln!("  {}: {}", url, err);
+        }
+    }
+    if !failures.is_empty() {
+        return Err(CrawlForgeError::DirectoriesFailed(failures.len()));
+    }
+    if !file_failures.is_empty() {
+        return Err(CrawlForgeError::FilesFailed(file_failures.len()));
+    }
+    Ok(())
+}
+
+fn download(opt: DownloadOpt) -> Result<(), CrawlForgeError> {
+    let contents = fs::read_to_string(&opt.index).map_err(CrawlForgeError::Io)?;
+    let entries = parse_jsonl_index(&contents)?;
+    let first = match entries.first() {
+        Some(entry) => &entry.url,
+        None => return Ok(()),
+    };
+
+    let client = Client::new(&opt.client, first)?;
+    let mut downloader = Downloader::new(client, &opt.output_dir);
+    downloader.keep_going = opt.keep_going;
+    if opt.lfs {
+        downloader.lfs = LfsMode::Fetch;
+    }
+    let downloaded = match &opt.archive {
+        Some(path) => downloader.archive_to(&entries, path)?,
+        None => downloader.download_all(&entries)?,
+    };
+    eprintln!(
+        "crawlforge: downloaded {} of {} files",
+        downloaded,
+        entries.len()
+    );
+
+    let failures = downloader.failures();
+    if failures.is_empty() {
+        return Ok(());
+    }
+    eprintln!("crawlforge: failed files:");
+    for (url, err) in failures {
+        eprintln!("  {}: {}", url, err);
+    }
+    Err(CrawlForgeError::FilesFailed(failures.len()))
+}
+
+fn ls(opt: LsOpt) -> Result<(), CrawlForgeError> {
+    let client = Client::new(&opt.client, &opt.url)?;
+    let rows = list_dir(&client, opt.forge, &strip_credentials(&opt.url))?;
+    write_rows(std::io::stdout(), &rows, opt.format)
+}
+
+fn diff(opt: DiffOpt) -> Result<(), CrawlForgeError> {
+    IndexDiff::from_files(&opt)?.write(std::io::stdout(), opt.format)
+}
+
+fn refs(opt: RefsOpt) -> Result<(), CrawlForgeError> {
+    let client = Client::new(&opt.client, &opt.url)?;
+    for r in list_refs(&client, opt.forge, &strip_credentials(&opt.url))? {
+        println!("{}\t{}\t{}", r.kind, r.name, r.sha);
     }
     Ok(())
 }
 
+fn history(opt: HistoryOpt) -> Result<(), CrawlForgeError> {
+    let mut client = Client::new(&opt.client, &opt.url)?;
+    let root = strip_credentials(&opt.url);
+    let robots = if opt.ignore_robots {
+        None
+    } else {
+        let robots = Robots::fetch(&client, &root)?;
+        if let Some(delay) = robots.crawl_delay {
+            client.set_min_delay(delay);
+        }
+        Some(robots)
+    };
+
+    let revisions = list_revisions(
+        &client,
+        opt.forge,
+        &root,
+        opt.max_revisions,
+        robots.as_ref(),
+    )?;
+    eprintln!("crawlforge: importing {} revisions", revisions.len());
+    match &opt.to_git {
+        None => {
+            let mut stream = FastImport::new(io::stdout());
+            import_history(client, opt.forge, &root, robots, &revisions, &mut stream)?;
+            stream.finish()?;
+        }
+        Some(dir) => {
+            let mut repo = GitRepo::init(dir)?;
+            let mut stream = FastImport::new(repo.stdin());
+            let branch = import_history(client, opt.forge, &root, robots, &revisions, &mut stream)?;
+            stream.finish()?;
+            repo.finish(&branch)?;
+            eprintln!(
+                "crawlforge: committed {} revisions to {}",
+                revisions.len(),
+                dir.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn verify(opt: VerifyOpt) -> Result<(), CrawlForgeError> {
+    let contents = fs::read_to_string(&opt.index).map_err(CrawlForgeError::Io)?;
+    let entries = parse_jsonl_index(&contents)?;
+    let mut bad = 0;
+    for entry in &entries {
+        if let Some(problem) = verify_entry(&opt.output_dir, entry)? {
+            println!("{}\t{}", entry.path, problem);
+            bad += 1;
+        }
+    }
+    eprintln!(
+        "crawlforge: verified {} files, {} bad",
+        entries.len() - bad,
+        bad
+    );
+    if bad > 0 {
+        return Err(CrawlForgeError::FilesFailed(bad));
+    }
+    Ok(())
+}
+
+/// Prints the library's warnings to stderr, like crawlforge's other messages
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            let level = match record.level() {
+                log::Level::Error => "error",
+                _ => "warning",
+            };
+            eprintln!("crawlforge: {}: {}", level, record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
 fn main() {
-    let opt = CrawlForgeOpt::from_args();
-    std::process::exit(match crawl_forge_dir(opt.forge, opt.url) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Warn);
+    }
+
+    let result = match CrawlForgeOpt::from_args_or_crawl(std::env::args_os()) {
+        CrawlForgeOpt::Crawl(opt) => crawl(opt),
+        CrawlForgeOpt::Download(opt) => download(opt),
+        CrawlForgeOpt::Ls(opt) => ls(opt),
+        CrawlForgeOpt::Diff(opt) => diff(opt),
+        CrawlForgeOpt::Refs(opt) => refs(opt),
+        CrawlForgeOpt::History(opt) => history(opt),
+        CrawlForgeOpt::Verify(opt) => verify(opt),
+    };
+    std::process::exit(match result {
         Ok(_) => 0,
         Err(err) => {
             eprintln!("crawlforge: error: {}", err);
@@ -0,0 +1,333 @@
+use crate::download::local_path;
+use crate::since::Change;
+use crate::{CrawlForgeError, Entry};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The format of the index printed while crawling
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// One raw file URL per line, suitable for wget --input-file
+    Plain,
+    /// One JSON object per line, one line per Entry
+    Jsonl,
+    /// An aria2c --input-file
+    Aria2,
+    /// A curl --config file
+    Curl,
+}
+
+impl FromStr for OutputFormat {
+    type Err = CrawlForgeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(OutputFormat::Plain),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "aria2" => Ok(OutputFormat::Aria2),
+            "curl" => Ok(OutputFormat::Curl),
+            _ => Err(CrawlForgeError::ParseFormatError(s.to_string())),
+        }
+    }
+}
+
+/// A line of a jsonl index written with --since-index, saying how the entry
+/// changed
+#[derive(Serialize)]
+struct ChangeLine<'a> {
+    #[serde(flatten)]
+    entry: &'a Entry,
+    change: Change,
+}
+
+/// A line of a jsonl index, which may say how the entry changed
+#[derive(Deserialize)]
+struct IndexLine {
+    #[serde(flatten)]
+    entry: Entry,
+    #[serde(default)]
+    change: Option<Change>,
+}
+
+/// Writes the index of a crawl, one entry at a time
+pub struct IndexWriter<W: Write> {
+    out: W,
+    format: OutputFormat,
+
+    /// Directory the downloader should save files under (aria2, curl)
+    pub dir: Option<PathBuf>,
+
+    /// Extra "Name: value" HTTP headers for the downloader (aria2, curl)
+    pub headers: Vec<String>,
+
+    /// Netscape cookies.txt for the downloader (curl)
+    pub cookies: Option<PathBuf>,
+}
+
+impl<W: Write> IndexWriter<W> {
+    pub fn new(out: W, format: OutputFormat) -> Self {
+        IndexWriter {
+            out,
+            format,
+            dir: None,
+            headers: Vec::new(),
+            cookies: None,
+        }
+    }
+
+    /// Returns the underlying writer
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Writes anything the format needs before the first entry
+    pub fn write_preamble(&mut self) -> Result<(), CrawlForgeError> {
+        if self.format != OutputFormat::Curl {
+            return Ok(());
+        }
+        writeln!(self.out, "create-dirs").map_err(CrawlForgeError::Io)?;
+        for header in &self.headers {
+            writeln!(self.out, "header = {}", curl_quote(header)).map_err(CrawlForgeError::Io)?;
+        }
+        if let Some(cookies) = &self.cookies {
+            let cookies = cookies.to_string_lossy();
+            writeln!(self.out, "cookie = {}", curl_quote(&cookies)).map_err(CrawlForgeError::Io)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a single entry of the index
+    pub fn write_entry(&mut self, entry: &Entry) -> Result<(), CrawlForgeError> {
+        match self.format {
+            OutputFormat::Plain => writeln!(self.out, "{}", entry.url).map_err(CrawlForgeError::Io),
+            OutputFormat::Jsonl => {
+                serde_json::to_writer(&mut self.out, entry).map_err(CrawlForgeError::Json)?;
+                writeln!(self.out).map_err(CrawlForgeError::Io)
+            }
+            OutputFormat::Aria2 => {
+                check_path(&entry.path)?;
+                self.write_aria2(entry).map_err(CrawlForgeError::Io)
+            }
+            OutputFormat::Curl => {
+                check_path(&entry.path)?;
+                let output = match &self.dir {
+                    Some(dir) => dir.join(&entry.path).to_string_lossy().into_owned(),
+                    None => entry.path.clone(),
+                };
+                writeln!(self.out, "url = {}", curl_quote(entry.url.as_str()))
+                    .and_then(|_| writeln!(self.out, "output = {}", curl_quote(&output)))
+                    .map_err(CrawlForgeError::Io)
+            }
+        }
+    }
+
+    /// Writes an entry of an index compared with a previous one. The jsonl
+    /// index gets every entry with a change field, so it can be compared with
+    /// next time, and the others only get what needs downloading.
+    pub fn write_change(&mut self, entry: &Entry, change: Change) -> Result<(), CrawlForgeError> {
+        match (self.format, change) {
+            (OutputFormat::Jsonl, _) => {
+                let line = ChangeLine { entry, change };
+                serde_json::to_writer(&mut self.out, &line).map_err(CrawlForgeError::Json)?;
+                writeln!(self.out).map_err(CrawlForgeError::Io)
+            }
+            (_, Change::Added) | (_, Change::Modified) => self.write_entry(entry),
+            (_, Change::Unchanged) | (_, Change::Removed) => Ok(()),
+        }
+    }
+
+    fn write_aria2(&mut self, entry: &Entry) -> std::io::Result<()> {
+        writeln!(self.out, "{}", entry.url)?;
+        if let Some(dir) = &self.dir {
+            writeln!(self.out, "  dir={}", dir.display())?;
+        }
+        writeln!(self.out, "  out={}", entry.path)?;
+        for header in &self.headers {
+            writeln!(self.out, "  header={}", header)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether a "Name: value" header carries credentials, which are
+/// left out of the index so it can be shared
+pub fn is_credential_header(header: &str) -> bool {
+    let name = header.split(':').next().unwrap_or_default().trim();
+    ["authorization", "proxy-authorization", "cookie"]
+        .iter()
+        .any(|n| name.eq_ignore_ascii_case(n))
+}
+
+/// Parses an index written with --format jsonl, leaving out entries marked
+/// as removed
+pub fn parse_jsonl_index(contents: &str) -> Result<Vec<Entry>, CrawlForgeError> {
+    let mut entries = Vec::new();
+    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+        let line: IndexLine = serde_json::from_str(line).map_err(CrawlForgeError::Json)?;
+        if line.change != Some(Change::Removed) {
+            entries.push(line.entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Fails unless path is safe to give a downloader to save to: relative,
+/// without "..", and without control characters, which would let a newline
+/// in a remote file name start another aria2 option or URL
+fn check_path(path: &str) -> Result<(), CrawlForgeError> {
+    if path.contains(char::is_control) {
+        return Err(CrawlForgeError::UnsafePath(path.escape_debug().to_string()));
+    }
+    local_path(Path::new(""), path).map(|_| ())
+}
+
+/// Quotes a curl config file parameter
+fn curl_quote(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ForgeKind;
+
+    fn entry() -> Entry {
+        Entry {
+            path: "AGL/metalayers/foofile".to_string(),
+            url: url::Url::parse("http://10.0.0.1:8080/raw/AGL/metalayers/foofile").unwrap(),
+            forge: ForgeKind::OpenGrok,
+            git_ref: None,
+            sha: None,
+            size: Some(1536),
+            size_rounded: false,
+            date: None,
+        }
+    }
+
+    fn write(writer: &mut IndexWriter<Vec<u8>>) -> String {
+        writer.write_preamble().unwrap();
+        writer.write_entry(&entry()).unwrap();
+        String::from_utf8(writer.out.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_write_entry_plain() {
+        let mut writer = IndexWriter::new(Vec::new(), OutputFormat::Plain);
+        assert_eq!(
+            write(&mut writer),
+            "http://10.0.0.1:8080/raw/AGL/metalayers/foofile\n"
+        );
+    }
+
+    #[test]
+    fn test_write_entry_jsonl() {
+        let mut writer = IndexWriter::new(Vec::new(), OutputFormat::Jsonl);
+        assert_eq!(
+            write(&mut writer),
+            concat!(
+                r#"{"path":"AGL/metalayers/foofile","#,
+                r#""url":"http://10.0.0.1:8080/raw/AGL/metalayers/foofile","#,
+                r#""forge":"opengrok","ref":null,"sha":null,"size":1536,"date":null}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_jsonl_index() {
+        let mut writer = IndexWriter::new(Vec::new(), OutputFormat::Jsonl);
+        let index = write(&mut writer) + "\n";
+        assert_eq!(parse_jsonl_index(&index).unwrap(), vec![entry()]);
+        assert!(parse_jsonl_index("http://10.0.0.1/raw/foo\n").is_err());
+    }
+
+    #[test]
+    fn test_write_change() {
+        let mut writer = IndexWriter::new(Vec::new(), OutputFormat::Jsonl);
+        writer.write_change(&entry(), Change::Unchanged).unwrap();
+        writer.write_change(&entry(), Change::Removed).unwrap();
+        let index = String::from_utf8(writer.into_inner()).unwrap();
+        assert!(index
+            .lines()
+            .next()
+            .unwrap()
+            .ends_with(r#""change":"unchanged"}"#));
+        // Removed entries aren't there any more
+        assert_eq!(parse_jsonl_index(&index).unwrap(), vec![entry()]);
+
+        let mut writer = IndexWriter::new(Vec::new(), OutputFormat::Plain);
+        writer.write_change(&entry(), Change::Unchanged).unwrap();
+        writer.write_change(&entry(), Change::Modified).unwrap();
+        let index = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(index.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_write_entry_aria2() {
+        let mut writer = IndexWriter::new(Vec::new(), OutputFormat::Aria2);
+        writer.dir = Some(PathBuf::from("mirror"));
+        writer.headers = vec!["Accept: text/plain".to_string()];
+        assert_eq!(
+            write(&mut writer),
+            concat!(
+                "http://10.0.0.1:8080/raw/AGL/metalayers/foofile\n",
+                "  dir=mirror\n",
+                "  out=AGL/metalayers/foofile\n",
+                "  header=Accept: text/plain\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_is_credential_header() {
+        assert!(is_credential_header("Authorization: Basic Zm9vOmJhcg=="));
+        assert!(is_credential_header("cookie:a=b"));
+        assert!(is_credential_header(" Proxy-Authorization : x"));
+        assert!(!is_credential_header("Accept: text/plain"));
+        assert!(!is_credential_header("X-Authorization-Hint: x"));
+    }
+
+    #[test]
+    fn test_write_entry_unsafe_path() {
+        for format in [OutputFormat::Aria2, OutputFormat::Curl] {
+            let mut writer = IndexWriter::new(Vec::new(), format);
+            let mut e = entry();
+            e.path = "foo\n  out=/etc/passwd".to_string();
+            assert!(writer.write_entry(&e).is_err());
+            e.path = "../escape".to_string();
+            assert!(writer.write_entry(&e).is_err());
+            assert!(writer.out.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_curl_quote() {
+        assert_eq!(curl_quote("a\"b\\c\nd"), "\"a\\\"b\\\\c\\nd\"");
+    }
+
+    #[test]
+    fn test_write_entry_curl() {
+        let mut writer = IndexWriter::new(Vec::new(), OutputFormat::Curl);
+        writer.dir = Some(PathBuf::from("mirror"));
+        writer.headers = vec!["X-Quote: \"hi\"".to_string()];
+        writer.cookies = Some(PathBuf::from("cookies.txt"));
+        assert_eq!(
+            write(&mut writer),
+            concat!(
+                "create-dirs\n",
+                "header = \"X-Quote: \\\"hi\\\"\"\n",
+                "cookie = \"cookies.txt\"\n",
+                "url = \"http://10.0.0.1:8080/raw/AGL/metalayers/foofile\"\n",
+                "output = \"mirror/AGL/metalayers/foofile\"\n",
+            )
+        );
+    }
+}
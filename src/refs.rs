@@ -0,0 +1,146 @@
+use crate::client::{Client, ClientOpt};
+use crate::{CrawlForgeError, ForgeKind};
+use serde::Deserialize;
+use structopt::StructOpt;
+
+/// How many refs to ask the GitHub API for at once, its maximum
+const PER_PAGE: usize = 100;
+
+#[derive(StructOpt, Debug)]
+pub struct RefsOpt {
+    /// URL of the repository, e.g. https://github.com/tompreston/sup/
+    pub url: url::Url,
+
+    /// Type of git forge
+    #[structopt(short, long, env, default_value = "github")]
+    pub forge: ForgeKind,
+
+    #[structopt(flatten)]
+    pub client: ClientOpt,
+}
+
+/// A branch or tag
+#[derive(Clone, Debug, PartialEq)]
+pub struct GitRef {
+    /// "branch" or "tag"
+    pub kind: &'static str,
+    pub name: String,
+    /// Commit the ref points at
+    pub sha: String,
+}
+
+/// A ref as returned by the GitHub branches and tags APIs
+#[derive(Deserialize)]
+struct ApiRef {
+    name: String,
+    commit: ApiCommit,
+}
+
+#[derive(Deserialize)]
+struct ApiCommit {
+    sha: String,
+}
+
+/// Returns the branches and then the tags of the repository at url
+pub fn list_refs(
+    client: &Client,
+    forge: ForgeKind,
+    url: &url::Url,
+) -> Result<Vec<GitRef>, CrawlForgeError> {
+    if forge != ForgeKind::GitHub {
+        return Err(CrawlForgeError::UnsupportedCommand(
+            "refs".to_string(),
+            forge,
+        ));
+    }
+    let api = github_api_url(url)?;
+    let mut refs = Vec::new();
+    for (kind, endpoint) in [("branch", "branches"), ("tag", "tags")] {
+        let mut u = api.join(endpoint).map_err(|_| bad_url(url))?;
+        u.query_pairs_mut()
+            .append_pair("per_page", &PER_PAGE.to_string());
+        let mut next = Some(u);
+        while let Some(u) = next {
+            let (body, next_page) = client.get_text_page(&u)?;
+            refs.extend(parse_refs(kind, &body)?);
+            next = next_page;
+        }
+    }
+    Ok(refs)
+}
+
+/// Parses a page of the GitHub branches or tags API
+fn parse_refs(kind: &'static str, body: &str) -> Result<Vec<GitRef>, CrawlForgeError> {
+    let refs: Vec<ApiRef> = serde_json::from_str(body).map_err(CrawlForgeError::Json)?;
+    Ok(refs
+        .into_iter()
+        .map(|r| GitRef {
+            kind,
+            name: r.name,
+            sha: r.commit.sha,
+        })
+        .collect())
+}
+
+/// Returns the API URL of the GitHub repository at url, ending in "/". GitHub
+/// Enterprise serves the API under /api/v3.
+pub(crate) fn github_api_url(url: &url::Url) -> Result<url::Url, CrawlForgeError> {
+    let mut segments = url.path_segments().ok_or_else(|| bad_url(url))?;
+    let owner = segments.next().filter(|s| !s.is_empty());
+    let repo = segments.next().filter(|s| !s.is_empty());
+    let (owner, repo) = owner.zip(repo).ok_or_else(|| bad_url(url))?;
+
+    let mut api = url.clone();
+    api.set_query(None);
+    api.set_fragment(None);
+    let _ = api.set_username("");
+    let _ = api.set_password(None);
+    if url.host_str() == Some("github.com") {
+        api.set_host(Some("api.github.com"))
+            .map_err(|_| bad_url(url))?;
+        api.set_path(&format!("/repos/{}/{}/", owner, repo));
+    } else {
+        api.set_path(&format!("/api/v3/repos/{}/{}/", owner, repo));
+    }
+    Ok(api)
+}
+
+pub(crate) fn bad_url(url: &url::Url) -> CrawlForgeError {
+    CrawlForgeError::UrlCannotBeABase(url.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_github_api_url() {
+        let u = |s| url::Url::parse(s).unwrap();
+        assert_eq!(
+            github_api_url(&u("https://github.com/tompreston/sup/tree/master/src"))
+                .unwrap()
+                .as_str(),
+            "https://api.github.com/repos/tompreston/sup/"
+        );
+        assert_eq!(
+            github_api_url(&u("https://git.corp/team/repo"))
+                .unwrap()
+                .as_str(),
+            "https://git.corp/api/v3/repos/team/repo/"
+        );
+        assert!(github_api_url(&u("https://github.com/tompreston/")).is_err());
+    }
+
+    #[test]
+    fn test_parse_refs() {
+        let body = r#"[{"name":"master","commit":{"sha":"2189dc2","url":"x"},"protected":false}]"#;
+        assert_eq!(
+            parse_refs("branch", body).unwrap(),
+            vec![GitRef {
+                kind: "branch",
+                name: "master".to_string(),
+                sha: "2189dc2".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use crate::client::Client;
+use crate::CrawlForgeError;
+use reqwest::StatusCode;
+use std::time::Duration;
+
+/// The longest Crawl-delay we'll honour, so a typo can't stall a crawl
+const MAX_CRAWL_DELAY: Duration = Duration::from_secs(60);
+
+/// The rules in a robots.txt which apply to us
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Robots {
+    /// (allow, path pattern) pairs
+    rules: Vec<(bool, String)>,
+
+    /// Time to wait between requests, if the site asks for one
+    pub crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    /// Fetches the robots.txt of the site hosting url. A robots.txt which
+    /// isn't there (a 4xx) allows everything. One which is unreachable (a 5xx
+    /// or 429, after retrying) is an error, since RFC 9309 says to assume
+    /// everything is disallowed then.
+    pub fn fetch(client: &Client, url: &url::Url) -> Result<Self, CrawlForgeError> {
+        let mut robots_url = url.clone();
+        robots_url.set_path("/robots.txt");
+        robots_url.set_query(None);
+
+        match client.get_text(&robots_url) {
+            Ok(body) => Ok(Robots::parse(&body, &client.user_agent_token())),
+            Err(CrawlForgeError::Reqwest(err))
+                if err.status().is_some_and(|status| {
+                    status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS
+                }) =>
+            {
+                Ok(Robots::default())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Parses a robots.txt, keeping the rules for the user agent's product
+    /// token, or for "*" if no group names it
+    pub fn parse(body: &str, user_agent: &str) -> Self {
+        let mut ours = Robots::default();
+        let mut anyone = Robots::default();
+        let mut found_ours = false;
+
+        // Which groups the current lines apply to
+        let mut for_us = false;
+        let mut for_anyone = false;
+        let mut in_agents = false;
+
+        for line in body.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let mut kv = line.splitn(2, ':');
+            let key = kv.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let value = kv.next().unwrap_or_default().trim();
+
+            match key.as_str() {
+                "user-agent" => {
+                    if !in_agents {
+                        for_us = false;
+                        for_anyone = false;
+                    }
+                    in_agents = true;
+                    // Groups may name a version too, e.g. "crawlforge/1.0"
+                    let agent = value.split(['/', ' ']).next().unwrap_or_default();
+                    if agent == "*" {
+                        for_anyone = true;
+                    } else if !agent.is_empty() && agent.eq_ignore_ascii_case(user_agent) {
+                        for_us = true;
+                        found_ours = true;
+                    }
+                }
+                "allow" | "disallow" | "crawl-delay" => {
+                    in_agents = false;
+                    for (applies, robots) in [(for_us, &mut ours), (for_anyone, &mut anyone)] {
+                        if !applies {
+                            continue;
+                        }
+                        match key.as_str() {
+                            "crawl-delay" => {
+                                robots.crawl_delay = value
+                                    .parse::<f64>()
+                                    .ok()
+                                    .and_then(|d| Duration::try_from_secs_f64(d).ok())
+                                    .map(|d| d.min(MAX_CRAWL_DELAY))
+                            }
+                            // An empty Disallow allows everything
+                            _ if value.is_empty() => (),
+                            _ => robots.rules.push((key == "allow", value.to_string())),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if found_ours {
+            ours
+        } else {
+            anyone
+        }
+    }
+
+    /// Returns whether we may fetch path. The longest matching rule wins, and
+    /// Allow wins a tie.
+    pub fn is_allowed(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|(_, pattern)| pattern_matches(pattern, path))
+            .max_by_key(|(allow, pattern)| (pattern.len(), *allow))
+            .is_none_or(|(allow, _)| *allow)
+    }
+}
+
+/// Matches a robots.txt path pattern, where "*" matches anything and a
+/// trailing "$" anchors the end
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !path.starts_with(first) {
+        return false;
+    }
+    let mut rest = &path[first.len()..];
+    let parts: Vec<_> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        let last = i == parts.len() - 1;
+        if last && anchored {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientOpt;
+    use crate::tests::server::scripted_server;
+    use structopt::StructOpt;
+
+    const ROBOTS_TXT: &str = "
+# Keep crawlers out of the history
+User-agent: *
+Disallow: /history/
+Disallow: /xref/*/private
+Allow: /xref/*/private/README$
+
+User-agent: crawlforge
+User-agent: wget
+Disallow: /search
+Crawl-delay: 2
+";
+
+    #[test]
+    fn test_robots_anyone() {
+        let robots = Robots::parse(ROBOTS_TXT, "somebot");
+        assert!(robots.is_allowed("/xref/AGL/metalayers/"));
+        assert!(!robots.is_allowed("/history/AGL/metalayers/poky"));
+        assert!(!robots.is_allowed("/xref/AGL/private/foo"));
+        assert!(robots.is_allowed("/xref/AGL/private/README"));
+        assert!(!robots.is_allowed("/xref/AGL/private/README.md"));
+        assert!(robots.is_allowed("/search"));
+        assert_eq!(robots.crawl_delay, None);
+    }
+
+    #[test]
+    fn test_robots_ours() {
+        let robots = Robots::parse(ROBOTS_TXT, "crawlforge");
+        assert!(robots.is_allowed("/history/AGL/metalayers/poky"));
+        assert!(!robots.is_allowed("/search?q=foo"));
+        assert_eq!(robots.crawl_delay, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_robots_agent_token() {
+        // Only whole product tokens match, whatever the case
+        let robots = Robots::parse("User-agent: bot\nDisallow: /\n", "crawlforge");
+        assert!(robots.is_allowed("/xref/"));
+        let robots = Robots::parse("User-agent: c\nDisallow: /\n", "crawlforge");
+        assert!(robots.is_allowed("/xref/"));
+        let robots = Robots::parse("User-agent: CrawlForge/1.0\nDisallow: /\n", "crawlforge");
+        assert!(!robots.is_allowed("/xref/"));
+    }
+
+    #[test]
+    fn test_robots_crawl_delay() {
+        let delay = |value: &str| {
+            let body = format!("User-agent: *\nCrawl-delay: {}\n", value);
+            Robots::parse(&body, "crawlforge").crawl_delay
+        };
+        assert_eq!(delay("0.5"), Some(Duration::from_millis(500)));
+        assert_eq!(delay("inf"), None);
+        assert_eq!(delay("1e400"), None);
+        assert_eq!(delay("-1"), None);
+        assert_eq!(delay("86400"), Some(MAX_CRAWL_DELAY));
+    }
+
+    #[test]
+    fn test_robots_fetch_status() {
+        let fetch = |status: &str| {
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            let (base, server) = scripted_server(&[&response]);
+            let opt = ClientOpt::from_iter(&["crawlforge", "--retries", "0"]);
+            let robots = Robots::fetch(&Client::new(&opt, &base).unwrap(), &base);
+            server.join().unwrap();
+            robots
+        };
+        assert_eq!(fetch("404 Not Found").unwrap(), Robots::default());
+        assert!(fetch("503 Service Unavailable").is_err());
+        assert!(fetch("429 Too Many Requests").is_err());
+    }
+
+    #[test]
+    fn test_robots_empty() {
+        let robots = Robots::parse("User-agent: *\nDisallow:\n", "crawlforge");
+        assert!(robots.is_allowed("/anything"));
+    }
+}
@@ -0,0 +1,287 @@
+use crate::output::parse_jsonl_index;
+use crate::{canonical_url, CrawlForgeError, Entry};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// How a file has changed since the previous index, as given in the change
+/// field of the jsonl index
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Modified,
+    Unchanged,
+    Removed,
+}
+
+/// The JSON Lines index of a previous crawl, which a re-crawl is compared with
+/// so only the changes are indexed
+#[derive(Debug, Default)]
+pub struct PreviousIndex {
+    /// Previous entries, by canonical URL
+    entries: HashMap<url::Url, Entry>,
+
+    /// Canonical URLs of the previous entries which are still there
+    seen: HashSet<url::Url>,
+
+    /// Forge paths of the directories listed in full, and of their
+    /// subdirectories
+    listed: HashMap<String, HashSet<String>>,
+
+    pub added: usize,
+    pub modified: usize,
+}
+
+impl PreviousIndex {
+    /// Loads an index written by --format jsonl
+    pub fn load(path: &Path) -> Result<Self, CrawlForgeError> {
+        let contents = fs::read_to_string(path).map_err(CrawlForgeError::Io)?;
+        Self::parse(&contents)
+    }
+
+    /// Parses the contents of a JSON Lines index
+    pub fn parse(contents: &str) -> Result<Self, CrawlForgeError> {
+        let mut index = PreviousIndex::default();
+        for entry in parse_jsonl_index(contents)? {
+            index.entries.insert(canonical_url(&entry.url), entry);
+        }
+        Ok(index)
+    }
+
+    /// Returns how entry has changed. If both it and the previous entry have
+    /// a blob SHA, which changes exactly when the contents do, that decides.
+    /// Otherwise their size, date and ref are compared, and files without a
+    /// size or date are always considered modified.
+    pub fn check(&mut self, entry: &Entry) -> Change {
+        let key = canonical_url(&entry.url);
+        let change = match self.entries.get(&key) {
+            None => Change::Added,
+            Some(prev) => match (&prev.sha, &entry.sha) {
+                (Some(prev_sha), Some(sha)) if prev_sha.eq_ignore_ascii_case(sha) => {
+                    Change::Unchanged
+                }
+                (Some(_), Some(_)) => Change::Modified,
+                _ if (entry.size.is_some() || entry.date.is_some())
+                    && prev.size == entry.size
+                    && prev.date == entry.date
+                    && prev.git_ref == entry.git_ref =>
+                {
+                    Change::Unchanged
+                }
+                _ => Change::Modified,
+            },
+        };
+        match change {
+            Change::Added => self.added += 1,
+            Change::Modified => self.modified += 1,
+            Change::Unchanged | Change::Removed => (),
+        }
+        self.seen.insert(key);
+        change
+    }
+
+    /// Notes that entry is still there, even if it isn't indexed
+    pub fn present(&mut self, entry: &Entry) {
+        self.seen.insert(canonical_url(&entry.url));
+    }
+
+    /// Notes that every file in the directory at forge path dir was passed to
+    /// check or present, and that it has subdirectories subdirs
+    pub fn listed(&mut self, dir: &str, subdirs: HashSet<String>) {
+        self.listed
+            .insert(dir.trim_matches('/').to_string(), subdirs);
+    }
+
+    /// Returns the previous entries under the directory at forge path dir if
+    /// nothing under it has changed, given the date of the last change under
+    /// it. If so, those entries are still there.
+    pub fn dir_unchanged(&mut self, dir: &str, date: DateTime<Utc>) -> Option<Vec<Entry>> {
+        let prefix = format!("{}/", dir.trim_matches('/'));
+        let under: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.path.starts_with(&prefix))
+            .collect();
+        // Every change under the directory, even deleting a file, makes its
+        // date later than that of the newest file we knew about
+        let newest = under.iter().map(|(_, e)| e.date).max().flatten();
+        let unchanged = !under.is_empty()
+            && under.iter().all(|(_, e)| e.date.is_some())
+            && newest.is_some_and(|newest| date <= newest);
+        if !unchanged {
+            return None;
+        }
+        let (keys, entries): (Vec<_>, Vec<_>) = under
+            .into_iter()
+            .map(|(k, e)| (k.clone(), e.clone()))
+            .unzip();
+        self.seen.extend(keys);
+        Some(entries)
+    }
+
+    /// Returns the previous entries which are gone. Files under directories
+    /// which weren't listed in full, e.g. because of --max-depth, robots.txt
+    /// or a failure, aren't necessarily gone, so aren't returned.
+    pub fn removed(&self) -> Vec<&Entry> {
+        self.unseen(true)
+    }
+
+    /// Returns the previous entries under directories which weren't listed in
+    /// full, which are carried forward as they were
+    pub fn not_visited(&self) -> Vec<&Entry> {
+        self.unseen(false)
+    }
+
+    fn unseen(&self, gone: bool) -> Vec<&Entry> {
+        let mut unseen: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(k, e)| !self.seen.contains(*k) && self.is_gone(&e.path) == gone)
+            .map(|(_, e)| e)
+            .collect();
+        unseen.sort_by(|a, b| a.path.cmp(&b.path));
+        unseen
+    }
+
+    /// Returns whether a file at path, which wasn't seen, is gone: either its
+    /// directory was listed, or the nearest directory above it which was
+    /// listed no longer has the subdirectory it was under
+    fn is_gone(&self, path: &str) -> bool {
+        let mut child = None;
+        let mut dir = parent(path);
+        loop {
+            if let Some(subdirs) = self.listed.get(dir) {
+                return child.is_none_or(|child| !subdirs.contains(child));
+            }
+            if dir.is_empty() {
+                return false;
+            }
+            child = Some(dir);
+            dir = parent(dir);
+        }
+    }
+}
+
+/// Returns the directory containing path, "" at the top
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDEX: &str = r#"
+{"path":"src/main.rs","url":"https://raw.githubusercontent.com/o/r/master/src/main.rs","forge":"github","ref":"master","sha":null,"size":null,"date":"2020-06-23T14:15:48Z"}
+{"path":"src/lib.rs","url":"https://raw.githubusercontent.com/o/r/master/src/lib.rs","forge":"github","ref":"master","sha":null,"size":null,"date":"2020-06-16T06:46:08Z"}
+{"path":"README.md","url":"https://raw.githubusercontent.com/o/r/master/README.md","forge":"github","ref":"master","sha":null,"size":null,"date":"2020-06-01T00:00:00Z"}
+"#;
+
+    fn date(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn entry(path: &str, d: &str) -> Entry {
+        Entry {
+            path: path.to_string(),
+            url: url::Url::parse("https://raw.githubusercontent.com/o/r/master/")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+            forge: crate::ForgeKind::GitHub,
+            git_ref: Some("master".to_string()),
+            sha: None,
+            size: None,
+            size_rounded: false,
+            date: Some(date(d)),
+        }
+    }
+
+    fn dirs(paths: &[&str]) -> HashSet<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_since_check() {
+        let mut index = PreviousIndex::parse(INDEX).unwrap();
+        let readme = entry("README.md", "2020-06-01T00:00:00Z");
+        assert_eq!(index.check(&readme), Change::Unchanged);
+        let lib = entry("src/lib.rs", "2020-07-01T00:00:00Z");
+        assert_eq!(index.check(&lib), Change::Modified);
+        let new = entry("src/new.rs", "2020-07-01T00:00:00Z");
+        assert_eq!(index.check(&new), Change::Added);
+        assert_eq!((index.added, index.modified), (1, 1));
+
+        // Nothing is gone until its directory is listed in full
+        assert!(index.removed().is_empty());
+        index.listed("", dirs(&["src"]));
+        assert!(index.removed().is_empty());
+        index.listed("src", dirs(&[]));
+        let removed: Vec<_> = index.removed().iter().map(|e| &e.path).collect();
+        assert_eq!(removed, ["src/main.rs"]);
+    }
+
+    #[test]
+    fn test_since_check_sha() {
+        let with_sha = |sha: &str| Entry {
+            sha: Some(sha.to_string()),
+            ..entry("README.md", "2020-06-01T00:00:00Z")
+        };
+        let mut index = PreviousIndex::default();
+        index
+            .entries
+            .insert(canonical_url(&with_sha("aaaa").url), with_sha("aaaa"));
+        // Same size and date, different contents
+        assert_eq!(index.check(&with_sha("bbbb")), Change::Modified);
+        assert_eq!(index.check(&with_sha("AAAA")), Change::Unchanged);
+        let later = Entry {
+            date: Some(date("2020-07-01T00:00:00Z")),
+            ..with_sha("aaaa")
+        };
+        assert_eq!(index.check(&later), Change::Unchanged);
+    }
+
+    #[test]
+    fn test_since_removed_dir() {
+        let mut index = PreviousIndex::parse(INDEX).unwrap();
+        index.present(&entry("README.md", "2020-06-01T00:00:00Z"));
+        // src/ was listed, but not crawled
+        index.listed("", dirs(&["src"]));
+        assert!(index.removed().is_empty());
+        assert_eq!(index.not_visited().len(), 2);
+        // src/ is gone
+        index.listed("", dirs(&[]));
+        assert_eq!(index.removed().len(), 2);
+    }
+
+    #[test]
+    fn test_since_dir_unchanged() {
+        let mut index = PreviousIndex::parse(INDEX).unwrap();
+        assert!(index
+            .dir_unchanged("src", date("2020-07-01T00:00:00Z"))
+            .is_none());
+        assert!(index
+            .dir_unchanged("doc", date("2020-06-01T00:00:00Z"))
+            .is_none());
+        index.listed("", dirs(&["src"]));
+        assert_eq!(index.removed().len(), 1);
+
+        let unchanged = index
+            .dir_unchanged("src", date("2020-06-23T14:15:48Z"))
+            .unwrap();
+        assert_eq!(unchanged.len(), 2);
+        let removed: Vec<_> = index.removed().iter().map(|e| &e.path).collect();
+        assert_eq!(removed, ["README.md"]);
+    }
+
+    #[test]
+    fn test_since_parse_error() {
+        assert!(matches!(
+            PreviousIndex::parse("not json\n"),
+            Err(CrawlForgeError::Json(_))
+        ));
+    }
+}
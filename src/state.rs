@@ -0,0 +1,110 @@
+use crate::{CrawlForgeError, Listing};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// The progress of a crawl, persisted to a file so an interrupted crawl can be
+/// resumed.
+///
+/// The file is a journal with one JSON Listing per line, appended as soon as
+/// each directory has been fetched. A line cut short by a crash is ignored and
+/// that directory is fetched again.
+pub struct CrawlState {
+    file: File,
+    listings: HashMap<url::Url, Listing>,
+}
+
+impl CrawlState {
+    /// Opens the state file, loading the listings of a previous run if any
+    pub fn open(path: &Path) -> Result<Self, CrawlForgeError> {
+        let mut listings = HashMap::new();
+        let mut partial_line = false;
+        if path.exists() {
+            let contents = fs::read(path).map_err(CrawlForgeError::Io)?;
+            partial_line = !contents.is_empty() && !contents.ends_with(b"\n");
+            for line in contents.split(|b| *b == b'\n') {
+                if let Ok(listing) = serde_json::from_slice::<Listing>(line) {
+                    listings.insert(listing.url.clone(), listing);
+                }
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(CrawlForgeError::Io)?;
+
+        // Terminate a partial line, so the next listing starts on its own line
+        if partial_line {
+            writeln!(file).map_err(CrawlForgeError::Io)?;
+        }
+
+        Ok(CrawlState { file, listings })
+    }
+
+    /// Returns the listing of a directory visited by a previous run
+    pub fn get(&self, dir: &url::Url) -> Option<&Listing> {
+        self.listings.get(dir)
+    }
+
+    /// Records a directory as visited
+    pub fn record(&mut self, listing: &Listing) -> Result<(), CrawlForgeError> {
+        let mut line = serde_json::to_vec(listing).map_err(CrawlForgeError::Json)?;
+        line.push(b'\n');
+        self.file.write_all(&line).map_err(CrawlForgeError::Io)?;
+        self.file.sync_data().map_err(CrawlForgeError::Io)?;
+        self.listings.insert(listing.url.clone(), listing.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(dir: &str) -> Listing {
+        Listing {
+            url: url::Url::parse(dir).unwrap(),
+            dirs: vec![],
+            entries: vec![],
+            dir_dates: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_state_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.jsonl");
+
+        let a = listing("http://10.0.0.1/xref/a/");
+        let b = listing("http://10.0.0.1/xref/b/");
+        let mut state = CrawlState::open(&path).unwrap();
+        state.record(&a).unwrap();
+        state.record(&b).unwrap();
+        drop(state);
+
+        // Simulate a crash half way through writing a listing
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, r#"{{"url":"http://10.0.0.1/xref/c/","di"#).unwrap();
+        drop(file);
+
+        let mut state = CrawlState::open(&path).unwrap();
+        assert_eq!(state.get(&a.url), Some(&a));
+        assert_eq!(state.get(&b.url), Some(&b));
+        let c = listing("http://10.0.0.1/xref/c/");
+        assert_eq!(state.get(&c.url), None);
+        state.record(&c).unwrap();
+        drop(state);
+
+        let state = CrawlState::open(&path).unwrap();
+        assert_eq!(state.get(&c.url), Some(&c));
+        drop(state);
+
+        // Resuming again doesn't add blank lines
+        let len = fs::metadata(&path).unwrap().len();
+        drop(CrawlState::open(&path).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+}
@@ -217,3 +217,28 @@ pub const BODY_OPENGROK: &str = r#"
 </body>
 </html>
 "#;
+
+/// A small OpenGrok listing whose files show sizes
+pub const BODY_OPENGROK_SIZES: &str = r#"
+<table id="dirlist" class="tablesorter tablesorter-default">
+<thead>
+<tr>
+<th class="sorter-false"></th>
+<th>Name</th>
+<th class="sorter-false"></th>
+<th class="sort-dates">Date</th>
+<th class="sort-groksizes">Size</th>
+<th>#Lines</th>
+<th>LOC</th>
+</tr>
+</thead>
+<tbody>
+<tr><td><p class="'r'"/></td><td><b><a href="..">..</a></b></td><td></td><td>04-Jun-2020</td><td>-</td></tr>
+<tr><td><p class="r"/></td><td><a href="sub/"><b>sub</b></a>/</td><td class="q"><a href="/history/AGL/metalayers/sub" title="History">H</a></td><td>05-Jun-2020</td><td>-</td><td class="numlines"></td><td class="loc"></td></tr>
+<tr><td><p class="r"/></td><td><a href="../"><b>up</b></a>/</td><td class="q"></td><td>04-Jun-2020</td><td>-</td><td class="numlines"></td><td class="loc"></td></tr>
+<tr><td><p class="r"/></td><td><a href="./"><b>here</b></a>/</td><td class="q"></td><td>04-Jun-2020</td><td>-</td><td class="numlines"></td><td class="loc"></td></tr>
+<tr><td><p class="p"/></td><td><a href="foofile"><b>foofile</b></a></td><td class="q"><a href="/history/AGL/metalayers/foofile" title="History">H</a></td><td>08-Jun-2020</td><td>1.5 KiB</td><td class="numlines">40</td><td class="loc">32</td></tr>
+<tr><td><p class="p"/></td><td><a href="bar.c"><b>bar.c</b></a></td><td class="q"><a href="/history/AGL/metalayers/bar.c" title="History">H</a></td><td>07-Jun-2020</td><td>123</td><td class="numlines">5</td><td class="loc">4</td></tr>
+</tbody>
+</table>
+"#;
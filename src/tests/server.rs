@@ -0,0 +1,48 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::OnceLock;
+use std::thread::{self, JoinHandle};
+
+/// Returns the URL of a local server which answers every request with a 500,
+/// so fetching from it fails the same way whatever the environment
+pub(crate) fn failing_server() -> url::Url {
+    static URL: OnceLock<url::Url> = OnceLock::new();
+    URL.get_or_init(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+            }
+        });
+        url::Url::parse(&format!("http://{}/", addr)).unwrap()
+    })
+    .clone()
+}
+
+/// Starts a local server which answers one request with each of responses in
+/// turn, then stops. The handle returns the head of each request.
+pub(crate) fn scripted_server(responses: &[&str]) -> (url::Url, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let responses: Vec<String> = responses.iter().map(|r| r.to_string()).collect();
+    let handle = thread::spawn(move || {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let len = stream.read(&mut request).unwrap_or(0);
+            requests.push(String::from_utf8_lossy(&request[..len]).into_owned());
+            let _ = stream.write_all(response.as_bytes());
+        }
+        requests
+    });
+    (
+        url::Url::parse(&format!("http://{}/", addr)).unwrap(),
+        handle,
+    )
+}
@@ -0,0 +1,165 @@
+use crate::CrawlForgeError;
+use fs2::FileExt;
+use std::cell::Cell;
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to check for a free slot when a host is at its limit
+const SLOT_POLL: Duration = Duration::from_millis(100);
+
+/// Spaces out requests, and caps how many requests all crawlforge processes
+/// on this machine make to a host at once
+pub struct Throttle {
+    interval: Duration,
+    last: Cell<Option<Instant>>,
+    max_per_host: Option<u32>,
+    pub(crate) lock_dir: PathBuf,
+}
+
+/// One of a host's request slots, held until dropped
+pub struct HostSlot {
+    _file: File,
+}
+
+impl Throttle {
+    /// Creates a throttle waiting delay between requests, and making no more
+    /// than max_rps requests per second
+    pub fn new(
+        delay: Duration,
+        max_rps: Option<f64>,
+        max_per_host: Option<u32>,
+    ) -> Result<Self, CrawlForgeError> {
+        Ok(Throttle {
+            interval: interval(delay, max_rps)?,
+            last: Cell::new(None),
+            max_per_host,
+            lock_dir: lock_dir(),
+        })
+    }
+
+    /// Raises the minimum time between requests to at least interval
+    pub fn set_min_interval(&mut self, interval: Duration) {
+        self.interval = self.interval.max(interval);
+    }
+
+    /// Sleeps until it's polite to make the next request
+    pub fn wait(&self) {
+        if let Some(last) = self.last.get() {
+            let elapsed = last.elapsed();
+            if elapsed < self.interval {
+                thread::sleep(self.interval - elapsed);
+            }
+        }
+        self.last.set(Some(Instant::now()));
+    }
+
+    /// Waits for a free request slot for the host of url
+    pub fn acquire(&self, url: &url::Url) -> Result<Option<HostSlot>, CrawlForgeError> {
+        if self.max_per_host.is_none() {
+            return Ok(None);
+        }
+        loop {
+            if let Some(slot) = self.try_acquire(url)? {
+                return Ok(Some(slot));
+            }
+            thread::sleep(SLOT_POLL);
+        }
+    }
+
+    pub(crate) fn try_acquire(&self, url: &url::Url) -> Result<Option<HostSlot>, CrawlForgeError> {
+        let max = match self.max_per_host {
+            Some(max) => max,
+            None => return Ok(None),
+        };
+        fs::create_dir_all(&self.lock_dir).map_err(CrawlForgeError::Io)?;
+
+        let host: String = format!(
+            "{}-{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        )
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+
+        for i in 0..max {
+            let path = self.lock_dir.join(format!("{}.{}.lock", host, i));
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(path)
+                .map_err(CrawlForgeError::Io)?;
+            if file.try_lock_exclusive().is_ok() {
+                return Ok(Some(HostSlot { _file: file }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Returns the directory to keep host slot locks in. It's per user, so one
+/// user can't hold another's slots, or stop them creating the directory.
+fn lock_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) {
+        return PathBuf::from(dir).join("crawlforge-locks");
+    }
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default();
+    std::env::temp_dir().join(format!("crawlforge-locks-{}", user))
+}
+
+/// Returns the minimum time between requests. A max_rps so small that the
+/// interval doesn't fit in a Duration is an error.
+fn interval(delay: Duration, max_rps: Option<f64>) -> Result<Duration, CrawlForgeError> {
+    let rps_interval = match max_rps.filter(|rps| *rps > 0.0) {
+        Some(rps) => Duration::try_from_secs_f64(1.0 / rps).map_err(|_| {
+            CrawlForgeError::InvalidOption("--max-requests-per-second", format!("{:?}", rps))
+        })?,
+        None => Duration::from_secs(0),
+    };
+    Ok(delay.max(rps_interval))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval() {
+        let secs = Duration::from_secs;
+        let interval = |delay, max_rps| interval(delay, max_rps).unwrap();
+        assert_eq!(interval(secs(0), None), secs(0));
+        assert_eq!(interval(secs(2), None), secs(2));
+        assert_eq!(interval(secs(0), Some(4.0)), Duration::from_millis(250));
+        assert_eq!(interval(secs(1), Some(4.0)), secs(1));
+        assert_eq!(interval(secs(0), Some(0.0)), secs(0));
+        assert!(super::interval(secs(0), Some(1e-300)).is_err());
+    }
+
+    #[test]
+    fn test_host_slots() {
+        let mut throttle = Throttle::new(Duration::from_secs(0), None, Some(2)).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        throttle.lock_dir = dir.path().to_path_buf();
+        let u = url::Url::parse("http://10.0.0.1:8080/xref/").unwrap();
+
+        let a = throttle.try_acquire(&u).unwrap();
+        let b = throttle.try_acquire(&u).unwrap();
+        assert!(a.is_some() && b.is_some());
+        assert!(throttle.try_acquire(&u).unwrap().is_none());
+
+        // Other hosts have their own slots
+        let other = url::Url::parse("http://10.0.0.2/xref/").unwrap();
+        assert!(throttle.try_acquire(&other).unwrap().is_some());
+
+        drop(a);
+        assert!(throttle.try_acquire(&u).unwrap().is_some());
+    }
+}
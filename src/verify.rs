@@ -0,0 +1,216 @@
+use crate::download::{local_path, SNIFF_LEN};
+use crate::lfs::{hex, parse_pointer, pointer};
+use crate::{is_html_path, looks_like_html, CrawlForgeError, Entry};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct VerifyOpt {
+    /// Index written by crawl --format jsonl
+    #[structopt(parse(from_os_str))]
+    pub index: PathBuf,
+
+    /// Directory the files were downloaded to, at their paths in the index
+    #[structopt(short, long, parse(from_os_str), default_value = ".")]
+    pub output_dir: PathBuf,
+}
+
+/// What is wrong with a downloaded file
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    Missing,
+    /// An HTML page, such as a login page, saved in place of the file
+    Html,
+    /// A Git LFS pointer saved in place of the file
+    LfsPointer,
+    Size {
+        expected: u64,
+        actual: u64,
+    },
+    Sha {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing"),
+            Problem::Html => write!(f, "HTML page instead of the file"),
+            Problem::LfsPointer => write!(f, "Git LFS pointer instead of the file"),
+            Problem::Size { expected, actual } => {
+                write!(f, "size {}, expected {}", actual, expected)
+            }
+            Problem::Sha { expected, actual } => {
+                write!(f, "git blob {}, expected {}", actual, expected)
+            }
+        }
+    }
+}
+
+/// Checks the file downloaded for entry under dir, against the size and git
+/// blob SHA-1 in the index where it has them. For a Git LFS object, the index
+/// has the SHA-1 of its pointer.
+pub fn verify_entry(dir: &Path, entry: &Entry) -> Result<Option<Problem>, CrawlForgeError> {
+    let path = local_path(dir, &entry.path)?;
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Some(Problem::Missing)),
+        Err(err) => return Err(CrawlForgeError::Io(err)),
+    };
+    let actual = file.metadata().map_err(CrawlForgeError::Io)?.len();
+    let mut head = Vec::new();
+    (&mut file)
+        .take(SNIFF_LEN)
+        .read_to_end(&mut head)
+        .map_err(CrawlForgeError::Io)?;
+
+    if !is_html_path(&entry.path) && looks_like_html(&head) {
+        return Ok(Some(Problem::Html));
+    }
+    if parse_pointer(&head).is_some() {
+        return Ok(Some(Problem::LfsPointer));
+    }
+    if let Some(expected) = entry
+        .size
+        .filter(|size| !size_matches(*size, actual, entry.size_rounded))
+    {
+        return Ok(Some(Problem::Size { expected, actual }));
+    }
+    if let Some(expected) = &entry.sha {
+        // The index has the blob of the file, or of its LFS pointer
+        let mut blob = sha1_smol::Sha1::new();
+        blob.update(format!("blob {}\0", actual).as_bytes());
+        let mut object = Sha256::new();
+        let mut chunk = head;
+        loop {
+            blob.update(&chunk);
+            object.update(&chunk);
+            chunk.clear();
+            let read = (&mut file)
+                .take(SNIFF_LEN)
+                .read_to_end(&mut chunk)
+                .map_err(CrawlForgeError::Io)?;
+            if read == 0 {
+                break;
+            }
+        }
+        let size = actual;
+        let actual = blob.digest().to_string();
+        let lfs_object = || git_blob_sha(pointer(&hex(&object.finalize()), size).as_bytes());
+        if !actual.eq_ignore_ascii_case(expected) && !lfs_object().eq_ignore_ascii_case(expected) {
+            return Ok(Some(Problem::Sha {
+                expected: expected.clone(),
+                actual,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns whether a file of actual bytes matches the size in a listing.
+/// OpenGrok rounds sizes of 1 KiB and over to one decimal place, e.g. "1.5
+/// KiB", so rounded sizes only have to be close.
+fn size_matches(expected: u64, actual: u64, rounded: bool) -> bool {
+    if rounded {
+        expected.abs_diff(actual) <= expected / 20
+    } else {
+        expected == actual
+    }
+}
+
+/// Returns the SHA-1 git gives a blob with contents, as by git hash-object
+pub fn git_blob_sha(contents: &[u8]) -> String {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(format!("blob {}\0", contents.len()).as_bytes());
+    hasher.update(contents);
+    hasher.digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lfs::pointer_for;
+    use crate::ForgeKind;
+    use std::fs;
+
+    fn entry(path: &str, size: Option<u64>, sha: Option<&str>) -> Entry {
+        Entry {
+            path: path.to_string(),
+            url: url::Url::parse("https://raw.githubusercontent.com/o/r/master/")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+            forge: ForgeKind::GitHub,
+            git_ref: Some("master".to_string()),
+            sha: sha.map(str::to_string),
+            size,
+            size_rounded: false,
+            date: None,
+        }
+    }
+
+    #[test]
+    fn test_git_blob_sha() {
+        assert_eq!(
+            git_blob_sha(b"/target\n"),
+            "ea8c4bf7f35f6f77f75d92ad8ce8349f6e81ddba"
+        );
+    }
+
+    #[test]
+    fn test_size_matches() {
+        assert!(size_matches(8, 8, false));
+        assert!(!size_matches(9, 8, false));
+        assert!(size_matches(1536, 1500, true));
+        assert!(!size_matches(1536, 1500, false));
+        assert!(!size_matches(1536, 1024, true));
+    }
+
+    #[test]
+    fn test_verify_entry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join(".gitignore"), "/target\n").unwrap();
+        fs::write(dir.join("login.rs"), "<!DOCTYPE html><html>Sign in</html>").unwrap();
+        let sha = "ea8c4bf7f35f6f77f75d92ad8ce8349f6e81ddba";
+
+        let ok = entry(".gitignore", Some(8), Some(sha));
+        assert_eq!(verify_entry(dir, &ok).unwrap(), None);
+
+        let truncated = entry(".gitignore", Some(9), None);
+        assert_eq!(
+            verify_entry(dir, &truncated).unwrap(),
+            Some(Problem::Size {
+                expected: 9,
+                actual: 8
+            })
+        );
+
+        let other = entry(".gitignore", None, Some(&"0".repeat(40)));
+        assert!(matches!(
+            verify_entry(dir, &other).unwrap(),
+            Some(Problem::Sha { .. })
+        ));
+
+        let pointer = pointer_for(b"hello\n");
+        fs::write(dir.join("image.bin"), &pointer).unwrap();
+        let lfs = entry("image.bin", None, None);
+        assert_eq!(verify_entry(dir, &lfs).unwrap(), Some(Problem::LfsPointer));
+        fs::write(dir.join("image.bin"), "hello\n").unwrap();
+        let pointer_sha = git_blob_sha(pointer.as_bytes());
+        let lfs = entry("image.bin", None, Some(&pointer_sha));
+        assert_eq!(verify_entry(dir, &lfs).unwrap(), None);
+
+        let login = entry("login.rs", None, None);
+        assert_eq!(verify_entry(dir, &login).unwrap(), Some(Problem::Html));
+
+        let missing = entry("missing.rs", None, None);
+        assert_eq!(verify_entry(dir, &missing).unwrap(), Some(Problem::Missing));
+    }
+}